@@ -1,9 +1,101 @@
-use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
-use kvs::{KvStore, KvsEngine, SledStore};
+use criterion::{
+    criterion_group, criterion_main,
+    measurement::{Measurement, ValueFormatter},
+    BatchSize, Criterion, Throughput,
+};
+use kvs::{
+    client::{ClientOptions, KvsClient, Protocol},
+    common::Request,
+    KvStore, KvsEngine, SledStore,
+};
 use rand::{distributions::Uniform, thread_rng, Rng};
-use std::time::Duration;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::{TcpListener, TcpStream},
+    process::Command,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
 use tempfile::TempDir;
 
+// count bytes currently allocated, to measure memory taken by the index
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// measure bytes instead of time, so that criterion reports regressions of
+// memory usage as well
+struct Bytes;
+
+impl Measurement for Bytes {
+    type Intermediate = usize;
+    type Value = u64;
+
+    fn start(&self) -> usize {
+        ALLOCATED.load(Ordering::SeqCst)
+    }
+
+    fn end(&self, start: usize) -> u64 {
+        ALLOCATED.load(Ordering::SeqCst).saturating_sub(start) as u64
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &BytesFormatter
+    }
+}
+
+struct BytesFormatter;
+
+impl ValueFormatter for BytesFormatter {
+    fn scale_values(&self, _: f64, _: &mut [f64]) -> &'static str {
+        "B"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _: f64,
+        _: &Throughput,
+        _: &mut [f64],
+    ) -> &'static str {
+        "B"
+    }
+
+    fn scale_for_machines(&self, _: &mut [f64]) -> &'static str {
+        "B"
+    }
+}
+
 fn random_data(count: usize, max_length: usize) -> Vec<String> {
     let mut input = Vec::with_capacity(count);
     for _ in 0..count {
@@ -12,7 +104,6 @@ fn random_data(count: usize, max_length: usize) -> Vec<String> {
             thread_rng()
                 .sample_iter(Uniform::new(char::from(32), char::from(126)))
                 .take(len)
-                .map(char::from)
                 .collect(),
         );
     }
@@ -36,7 +127,7 @@ fn bench_write(c: &mut Criterion) {
                 (temp_dir, KvStore::open(path).unwrap())
             },
             // use _temp_dir to delay its lifetime
-            |(_temp_dir, mut store)| {
+            |(_temp_dir, store)| {
                 for i in 0..DATA_COUNT {
                     store.set(key[i].clone(), value[i].clone()).unwrap();
                 }
@@ -51,7 +142,7 @@ fn bench_write(c: &mut Criterion) {
                 let path: std::path::PathBuf = temp_dir.path().into();
                 (temp_dir, SledStore::open(path).unwrap())
             },
-            |(_temp_dir, mut store)| {
+            |(_temp_dir, store)| {
                 for i in 0..DATA_COUNT {
                     store.set(key[i].clone(), value[i].clone()).unwrap();
                 }
@@ -80,13 +171,13 @@ fn bench_read(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().unwrap();
-                let mut store = KvStore::open(temp_dir.path()).unwrap();
+                let store = KvStore::open(temp_dir.path()).unwrap();
                 for i in 0..DATA_COUNT {
                     store.set(key[i].clone(), value[i].clone()).unwrap();
                 }
                 (temp_dir, store)
             },
-            |(_temp_dir, mut store)| {
+            |(_temp_dir, store)| {
                 for seq in &sequence {
                     store.get(key[*seq].clone()).unwrap();
                 }
@@ -98,13 +189,13 @@ fn bench_read(c: &mut Criterion) {
         b.iter_batched(
             || {
                 let temp_dir = TempDir::new().unwrap();
-                let mut store = SledStore::open(temp_dir.path()).unwrap();
+                let store = SledStore::open(temp_dir.path()).unwrap();
                 for i in 0..DATA_COUNT {
                     store.set(key[i].clone(), value[i].clone()).unwrap();
                 }
                 (temp_dir, store)
             },
-            |(_temp_dir, mut store)| {
+            |(_temp_dir, store)| {
                 for seq in &sequence {
                     store.get(key[*seq].clone()).unwrap();
                 }
@@ -116,9 +207,111 @@ fn bench_read(c: &mut Criterion) {
     group.finish();
 }
 
+// requests over a connection to a local server, one by one or pipelined
+fn bench_pipeline(c: &mut Criterion) {
+    const REQUEST_COUNT: usize = 1000;
+    const DEPTH: usize = 64;
+
+    // a port free for now, which the server is likely to get
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .unwrap();
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_kvs-server"))
+        .args(["--addr", &addr.to_string()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    // wait for the server to listen
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let requests: Vec<_> = (0..REQUEST_COUNT)
+        .map(|i| Request::Set {
+            key: format!("key{}", i % 100),
+            value: format!("value{i}"),
+            namespace: None,
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("pipeline");
+    group.throughput(Throughput::Elements(REQUEST_COUNT as u64));
+    for (name, protocol) in
+        [("binary", Protocol::Binary), ("json", Protocol::Json)]
+    {
+        let mut client = KvsClient::connect_with(
+            addr,
+            ClientOptions::new().protocol(protocol),
+        )
+        .unwrap();
+        group.bench_function(format!("{name}_sequential"), |b| {
+            b.iter(|| {
+                for request in &requests {
+                    client.request(request).unwrap();
+                }
+            })
+        });
+        group.bench_function(format!("{name}_pipelined"), |b| {
+            b.iter(|| client.pipeline(&requests, DEPTH).unwrap())
+        });
+    }
+    group.finish();
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+// bytes taken by every key after opening a store, mostly by its index
+fn bench_index_memory(c: &mut Criterion<Bytes>) {
+    const KEY_COUNT: u64 = 100000;
+
+    let mut group = c.benchmark_group("index_memory");
+    for (name, key_len) in [("short_keys", 16), ("long_keys", 64)] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStore::open(temp_dir.path()).unwrap();
+        for i in 0..KEY_COUNT {
+            store
+                .set(format!("{i:0key_len$}"), "value".to_owned())
+                .unwrap();
+        }
+        drop(store);
+
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                let start = ALLOCATED.load(Ordering::SeqCst);
+                let store = KvStore::open(temp_dir.path()).unwrap();
+                let used = ALLOCATED.load(Ordering::SeqCst) - start;
+                drop(store);
+                // one open is enough, as the result is deterministic
+                used as u64 / KEY_COUNT * iters
+            })
+        });
+    }
+
+    group.finish();
+}
+
 criterion_group!(
     name = benches;
     config = Criterion::default().measurement_time(Duration::from_secs(20));
     targets = bench_write, bench_read
 );
-criterion_main!(benches);
+criterion_group!(
+    name = network;
+    config = Criterion::default().sample_size(20);
+    targets = bench_pipeline
+);
+criterion_group!(
+    name = memory;
+    config = Criterion::default()
+        .with_measurement(Bytes)
+        .without_plots()
+        .sample_size(10)
+        .warm_up_time(Duration::from_millis(100));
+    targets = bench_index_memory
+);
+criterion_main!(benches, network, memory);
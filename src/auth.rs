@@ -0,0 +1,267 @@
+use crate::{
+    common::{Request, TxOp},
+    Error, Result,
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use std::{collections::HashMap, fmt, fs, path::Path};
+
+/// Access to keys granted by a rule, each level including the lower ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    /// Get and watch keys
+    Read,
+    /// Also set keys
+    Write,
+    /// Also remove keys
+    Admin,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+// keyspaces a rule applies to
+#[derive(Debug)]
+enum Keyspaces {
+    Default,
+    Named(String),
+    All,
+}
+
+// `access` to keys starting with `prefix` in `keyspaces`
+#[derive(Debug)]
+struct Rule {
+    access: Access,
+    keyspaces: Keyspaces,
+    prefix: String,
+}
+
+/// User of the server with ACL rules.
+#[derive(Debug)]
+pub struct User {
+    name: String,
+    // Argon2 hash of the password in the PHC string format
+    password: String,
+    rules: Vec<Rule>,
+}
+
+impl User {
+    /// Name of the user.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the user has `access` to all keys starting with `prefix` in
+    /// the keyspace named by `namespace`, or the default one if it is
+    /// `None`.
+    pub fn allows(
+        &self,
+        access: Access,
+        namespace: Option<&str>,
+        prefix: &str,
+    ) -> bool {
+        self.rules.iter().any(|rule| {
+            let keyspace = match (&rule.keyspaces, namespace) {
+                (Keyspaces::All, _) | (Keyspaces::Default, None) => true,
+                (Keyspaces::Named(name), Some(namespace)) => name == namespace,
+                _ => false,
+            };
+            rule.access >= access
+                && keyspace
+                && prefix.starts_with(&rule.prefix)
+        })
+    }
+
+    /// Fail with [`Error::Denied`] unless the user [allows](User::allows)
+    /// `access` to `prefix`.
+    pub fn check(
+        &self,
+        access: Access,
+        namespace: Option<&str>,
+        prefix: &str,
+    ) -> Result<()> {
+        if self.allows(access, namespace, prefix) {
+            return Ok(());
+        }
+        let keyspace = match namespace {
+            Some(name) => format!(" in keyspace {name:?}"),
+            None => String::new(),
+        };
+        Err(Error::Denied(format!(
+            "user {} has no {access} access to {prefix:?}{keyspace}",
+            self.name
+        )))
+    }
+
+    /// Check the access needed by `request` to every key it touches.
+    ///
+    /// A watch needs access to the whole prefix watched.
+    pub fn authorize(&self, request: &Request) -> Result<()> {
+        let (keys, namespace): (Vec<(Access, &str)>, _) = match request {
+            Request::Hello { .. } | Request::Auth { .. } => return Ok(()),
+            Request::Get { key, namespace } => {
+                (vec![(Access::Read, key)], namespace)
+            }
+            Request::Set { key, namespace, .. }
+            | Request::Incr { key, namespace, .. } => {
+                (vec![(Access::Write, key)], namespace)
+            }
+            Request::Remove { key, namespace } => {
+                (vec![(Access::Admin, key)], namespace)
+            }
+            Request::MGet { keys, namespace } => (
+                keys.iter()
+                    .map(|key| (Access::Read, key.as_str()))
+                    .collect(),
+                namespace,
+            ),
+            Request::MSet { pairs, namespace } => (
+                pairs
+                    .iter()
+                    .map(|(key, _)| (Access::Write, key.as_str()))
+                    .collect(),
+                namespace,
+            ),
+            Request::Transaction { ops, namespace } => (
+                ops.iter()
+                    .map(|op| match op {
+                        TxOp::Get { key } | TxOp::Check { key, .. } => {
+                            (Access::Read, key.as_str())
+                        }
+                        TxOp::Set { key, .. } => (Access::Write, key.as_str()),
+                        TxOp::Remove { key } => (Access::Admin, key.as_str()),
+                    })
+                    .collect(),
+                namespace,
+            ),
+            Request::Watch { prefix, namespace } => {
+                (vec![(Access::Read, prefix)], namespace)
+            }
+        };
+        keys.into_iter().try_for_each(|(access, key)| {
+            self.check(access, namespace.as_deref(), key)
+        })
+    }
+}
+
+/// Users allowed to access the server, read from a file of lines like
+///
+/// ```text
+/// # NAME PASSWORD-HASH RULE...
+/// alice $argon2id$v=19$m=19456,t=2,p=1$EvtheORBh8SslM2P0WT6kg$O21wPddxxx2Ac8RMY9ATUa7rIJXUEcZIK8EJF3TgzhU read: write:app/
+/// ```
+///
+/// where the password hash is an Argon2 PHC string, as returned by
+/// [`hash_password`] and printed by `kvs-server --hash-password`. A rule
+/// `LEVEL:PREFIX` grants access of `read`, `write` or `admin` to keys
+/// starting with `PREFIX` in the default keyspace, `LEVEL@NAMESPACE:PREFIX`
+/// to those in keyspace `NAMESPACE`, and `LEVEL@*:PREFIX` to those in all
+/// keyspaces. An empty prefix matches every key.
+#[derive(Debug)]
+pub struct Credentials {
+    users: HashMap<String, User>,
+    // checked against passwords of unknown users, so they take as long
+    dummy: String,
+}
+
+impl Credentials {
+    /// Read users from the file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Credentials> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for (n, line) in text.lines().enumerate() {
+            let invalid = |what: String| {
+                Error::Message(format!(
+                    "invalid credentials at {}:{}: {what}",
+                    path.display(),
+                    n + 1
+                ))
+            };
+            let mut fields = line.split_whitespace();
+            let Some(name) =
+                fields.next().filter(|name| !name.starts_with('#'))
+            else {
+                continue;
+            };
+            let password = fields
+                .next()
+                .filter(|hash| PasswordHash::new(hash).is_ok())
+                .ok_or_else(|| invalid("expected an Argon2 hash".into()))?;
+            let rules = fields
+                .map(|rule| {
+                    parse_rule(rule)
+                        .ok_or_else(|| invalid(format!("rule {rule}")))
+                })
+                .collect::<Result<_>>()?;
+            let user = User {
+                name: name.to_owned(),
+                password: password.to_owned(),
+                rules,
+            };
+            if users.insert(name.to_owned(), user).is_some() {
+                return Err(invalid(format!("duplicate user {name}")));
+            }
+        }
+        let dummy = hash_password("")?;
+        Ok(Credentials { users, dummy })
+    }
+
+    /// User of `name` if `password` is right.
+    ///
+    /// It takes about as long whether the user exists or not, and the hashes
+    /// are compared in constant time.
+    pub fn authenticate(&self, name: &str, password: &str) -> Result<&User> {
+        let user = self.users.get(name);
+        let hash = user.map_or(&self.dummy, |user| &user.password);
+        // parsed when the file was read
+        let hash = PasswordHash::new(hash).unwrap();
+        let verified = Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok();
+        user.filter(|_| verified).ok_or_else(|| {
+            Error::Denied("invalid username or password".to_owned())
+        })
+    }
+}
+
+/// Hash `password` with Argon2id and a random salt into a PHC string for
+/// the [credentials](Credentials) file.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| Error::Message(format!("cannot hash password: {e}")))?;
+    Ok(hash.to_string())
+}
+
+// `LEVEL[@NAMESPACE]:PREFIX`
+fn parse_rule(rule: &str) -> Option<Rule> {
+    let (head, prefix) = rule.split_once(':')?;
+    let (level, keyspaces) = match head.split_once('@') {
+        Some((level, "*")) => (level, Keyspaces::All),
+        Some((_, "")) => return None,
+        Some((level, name)) => (level, Keyspaces::Named(name.to_owned())),
+        None => (head, Keyspaces::Default),
+    };
+    let access = match level {
+        "read" => Access::Read,
+        "write" => Access::Write,
+        "admin" => Access::Admin,
+        _ => return None,
+    };
+    Some(Rule {
+        access,
+        keyspaces,
+        prefix: prefix.to_owned(),
+    })
+}
@@ -1,10 +1,12 @@
-use clap::{arg, command, Arg, ArgMatches, Command};
-use kvs::common::{Request, Response};
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
+use clap::{arg, command, value_parser, Arg, ArgMatches, Command};
+use kvs::{
+    client::{self, ClientOptions, Protocol},
+    common::{ErrorCode, Request, Response, TxOp},
+    Event,
+};
 use std::{
-    io::{BufReader, BufWriter, Write},
-    net::TcpStream,
+    io::{self, Write},
+    path::PathBuf,
 };
 
 fn main() -> kvs::Result<()> {
@@ -15,6 +17,45 @@ fn main() -> kvs::Result<()> {
         .help("IP address and port")
         .required(false);
     let matches = command!() // requires `cargo` feature
+        .arg(
+            arg!(--namespace <NAME> "Keyspace of keys, the default if absent")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--protocol <PROTOCOL> "Encoding of messages")
+                .value_parser(["binary", "json"])
+                .default_value("binary")
+                .global(true),
+        )
+        .arg(
+            arg!(--ca <FILE> "PEM certificates of CAs to connect over TLS")
+                .value_parser(value_parser!(PathBuf))
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--cert <FILE> "PEM certificate chain of the client for TLS")
+                .value_parser(value_parser!(PathBuf))
+                .requires_all(["ca", "key"])
+                .global(true),
+        )
+        .arg(
+            arg!(--key <FILE> "PEM private key of the client for TLS")
+                .value_parser(value_parser!(PathBuf))
+                .requires_all(["ca", "cert"])
+                .global(true),
+        )
+        .arg(
+            arg!(--user <NAME> "User to authenticate as")
+                .requires("password")
+                .global(true),
+        )
+        .arg(
+            arg!(--password <PASSWORD> "Password of the user")
+                .requires("user")
+                .global(true),
+        )
         .subcommands(&[
             Command::new("set")
                 .about("Set the value of a string key to a string")
@@ -29,6 +70,38 @@ fn main() -> kvs::Result<()> {
             Command::new("rm")
                 .about("Remove a given string key")
                 .args(&[arg!(<KEY> "A string key"), addr_arg.clone()]),
+            Command::new("mget")
+                .about("Get string values of string keys")
+                .args(&[arg!(<KEY> ... "String keys"), addr_arg.clone()]),
+            Command::new("mset")
+                .about("Set values of string keys to strings")
+                .args(&[
+                    arg!(<PAIR> ... "String keys, each followed by its value"),
+                    addr_arg.clone(),
+                ]),
+            Command::new("incr")
+                .about("Add a delta to the integer value of a string key")
+                .args(&[
+                    arg!(<KEY> "A string key"),
+                    arg!([DELTA] "An integer, 1 if absent")
+                        .value_parser(value_parser!(i64))
+                        .allow_negative_numbers(true),
+                    addr_arg.clone(),
+                ]),
+            Command::new("tx")
+                .about("Run operations in one transaction")
+                .args(&[
+                    arg!(<OP> ... "`get KEY`, `set KEY VALUE`, `rm KEY`, \
+                        `check KEY VALUE` or `absent KEY`"),
+                    addr_arg.clone(),
+                ])
+                .after_help(
+                    "Values got are printed in order. Any failed operation \
+                    or check aborts the transaction.",
+                ),
+            Command::new("watch")
+                .about("Print changes of keys starting with a prefix")
+                .args(&[arg!(<PREFIX> "A key prefix"), addr_arg.clone()]),
         ])
         .get_matches();
 
@@ -46,13 +119,45 @@ fn main() -> kvs::Result<()> {
             let key = sub_m.get_one::<String>("KEY").unwrap();
             KvsClient::new(sub_m)?.remove(key.clone())
         }
+        Some(("mget", sub_m)) => {
+            let keys = sub_m.get_many::<String>("KEY").unwrap().cloned();
+            KvsClient::new(sub_m)?.get_many(keys.collect())
+        }
+        Some(("mset", sub_m)) => {
+            let args: Vec<_> =
+                sub_m.get_many::<String>("PAIR").unwrap().collect();
+            if args.len() % 2 != 0 {
+                eprintln!("missing value of key {}", args[args.len() - 1]);
+                std::process::exit(1);
+            }
+            let pairs = args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            KvsClient::new(sub_m)?.set_many(pairs)
+        }
+        Some(("incr", sub_m)) => {
+            let key = sub_m.get_one::<String>("KEY").unwrap();
+            let delta = sub_m.get_one::<i64>("DELTA").copied().unwrap_or(1);
+            KvsClient::new(sub_m)?.increment(key.clone(), delta)
+        }
+        Some(("tx", sub_m)) => {
+            let args: Vec<_> =
+                sub_m.get_many::<String>("OP").unwrap().collect();
+            let ops = parse_ops(&args)?;
+            KvsClient::new(sub_m)?.transaction(ops)
+        }
+        Some(("watch", sub_m)) => {
+            let prefix = sub_m.get_one::<String>("PREFIX").unwrap();
+            KvsClient::new(sub_m)?.watch(prefix.clone())
+        }
         _ => panic!(),
     }
 }
 
 struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    client: client::KvsClient,
+    namespace: Option<String>,
 }
 
 impl KvsClient {
@@ -60,45 +165,230 @@ impl KvsClient {
         let addr = matches
             .get_one::<String>("ip_port")
             .map_or(String::from("127.0.0.1:4000"), |x| x.clone());
-        let stream =
-            TcpStream::connect(addr).expect("unable to connect to {addr}");
+        let protocol =
+            match matches.get_one::<String>("protocol").unwrap().as_str() {
+                "binary" => Protocol::Binary,
+                _ => Protocol::Json,
+            };
+        let mut options = ClientOptions::new().protocol(protocol);
+        if let Some(ca) = matches.get_one::<PathBuf>("ca") {
+            let identity = matches
+                .get_one::<PathBuf>("cert")
+                .zip(matches.get_one::<PathBuf>("key"))
+                .map(|(cert, key)| (cert.as_path(), key.as_path()));
+            let config = kvs::tls::client_config(ca, identity)?;
+            options = options.tls(config, server_name(&addr));
+        }
+        if let Some(user) = matches.get_one::<String>("user") {
+            let password = matches.get_one::<String>("password").unwrap();
+            options = options.auth(user, password);
+        }
+        let client = client::KvsClient::connect_with(&addr, options)
+            .unwrap_or_else(|e| {
+                eprintln!("unable to connect to {addr}: {e}");
+                std::process::exit(1);
+            });
 
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(
-                stream.try_clone()?,
-            )),
-            writer: BufWriter::new(stream),
+            client,
+            namespace: matches.get_one::<String>("namespace").cloned(),
         })
     }
 
     fn set(&mut self, key: String, value: String) -> kvs::Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-
-        // ignore status
-        let Response::Status(_status) =
-            Response::deserialize(&mut self.reader)?;
-        Ok(())
+        let request = Request::Set {
+            key,
+            value,
+            namespace: self.namespace.clone(),
+        };
+        match self.send(&request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
     }
 
     fn get(&mut self, key: String) -> kvs::Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-
-        let Response::Status(status) = Response::deserialize(&mut self.reader)?;
-        println!("{status}");
-        Ok(())
+        let request = Request::Get {
+            key,
+            namespace: self.namespace.clone(),
+        };
+        match self.send(&request)? {
+            Response::Value(value) => {
+                println!("{}", value.as_deref().unwrap_or("Key not found"));
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
     }
 
     fn remove(&mut self, key: String) -> kvs::Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
+        let request = Request::Remove {
+            key,
+            namespace: self.namespace.clone(),
+        };
+        match self.send(&request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn get_many(&mut self, keys: Vec<String>) -> kvs::Result<()> {
+        let request = Request::MGet {
+            keys,
+            namespace: self.namespace.clone(),
+        };
+        match self.send(&request)? {
+            Response::Values(values) => {
+                print_values(values);
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> kvs::Result<()> {
+        let request = Request::MSet {
+            pairs,
+            namespace: self.namespace.clone(),
+        };
+        match self.send(&request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn increment(&mut self, key: String, delta: i64) -> kvs::Result<()> {
+        let request = Request::Incr {
+            key,
+            delta,
+            namespace: self.namespace.clone(),
+        };
+        match self.send(&request)? {
+            Response::Integer(sum) => {
+                println!("{sum}");
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn transaction(&mut self, ops: Vec<TxOp>) -> kvs::Result<()> {
+        let request = Request::Transaction {
+            ops,
+            namespace: self.namespace.clone(),
+        };
+        match self.send(&request)? {
+            Response::Values(values) => {
+                print_values(values);
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    fn watch(&mut self, prefix: String) -> kvs::Result<()> {
+        let request = Request::Watch {
+            prefix,
+            namespace: self.namespace.clone(),
+        };
+        let mut response = self.send(&request)?;
+        let mut stdout = io::stdout().lock();
+        loop {
+            match response {
+                Response::Event(Event {
+                    key,
+                    value: Some(value),
+                    seq,
+                }) => writeln!(stdout, "{seq} set {key} {value}")?,
+                Response::Event(Event {
+                    key,
+                    value: None,
+                    seq,
+                }) => writeln!(stdout, "{seq} rm {key}")?,
+                response => return Err(unexpected(response)),
+            }
+            stdout.flush()?;
+            response = self.receive()?;
+        }
+    }
+
+    // send `request` and receive the first response
+    fn send(&mut self, request: &Request) -> kvs::Result<Response> {
+        Ok(check(self.client.request(request)?))
+    }
+
+    // receive a response, and exit on an error
+    fn receive(&mut self) -> kvs::Result<Response> {
+        Ok(check(self.client.receive()?))
+    }
+}
 
-        let Response::Status(status) = Response::deserialize(&mut self.reader)?;
-        if !status.is_empty() {
-            eprintln!("{status}");
+// pass `response` through, or exit if it is an error
+fn check(response: Response) -> Response {
+    match response {
+        Response::Error {
+            code: ErrorCode::NotFound,
+            ..
+        } => {
+            eprintln!("Key not found");
+            std::process::exit(1);
+        }
+        Response::Error { message, .. } => {
+            eprintln!("{message}");
             std::process::exit(1);
         }
-        Ok(())
+        response => response,
+    }
+}
+
+// print values of keys, one per line
+fn print_values(values: Vec<Option<String>>) {
+    for value in values {
+        println!("{}", value.as_deref().unwrap_or("Key not found"));
     }
 }
+
+// host of `addr` without its port, naming the server in its certificate
+fn server_name(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+// parse operations of `tx` like `get a set b 1`
+fn parse_ops(args: &[&String]) -> kvs::Result<Vec<TxOp>> {
+    let mut args = args.iter().map(|arg| arg.to_string());
+    let mut ops = Vec::new();
+    while let Some(op) = args.next() {
+        let mut operand = || {
+            args.next().ok_or_else(|| {
+                kvs::Error::Message(format!("missing operand of `{op}`"))
+            })
+        };
+        ops.push(match op.as_str() {
+            "get" => TxOp::Get { key: operand()? },
+            "set" => TxOp::Set {
+                key: operand()?,
+                value: operand()?,
+            },
+            "rm" => TxOp::Remove { key: operand()? },
+            "check" => TxOp::Check {
+                key: operand()?,
+                value: Some(operand()?),
+            },
+            "absent" => TxOp::Check {
+                key: operand()?,
+                value: None,
+            },
+            _ => {
+                return Err(kvs::Error::Message(format!(
+                    "unknown operation `{op}`"
+                )))
+            }
+        });
+    }
+    Ok(ops)
+}
+
+fn unexpected(response: Response) -> kvs::Error {
+    kvs::Error::Message(format!("unexpected response {response:?}"))
+}
@@ -1,71 +1,264 @@
-use clap::{command, Arg};
+use clap::{command, Arg, ArgAction, ArgMatches};
 use kvs::{
-    common::{Request, Response},
-    thread_pool::{NaiveThreadPool, ThreadPool},
-    KvStore, KvsEngine, SledStore,
+    auth::Credentials,
+    server::KvsServer,
+    thread_pool::{
+        NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool,
+    },
+    tls, Codec, EncryptionKey, IndexMode, KvStore, KvStoreOptions, KvsEngine,
+    SledStore, SyncMode,
 };
-use serde_json::Deserializer;
-use slog::{debug, error, info, o, Drain};
+use serde::Deserialize;
+use slog::{error, info, o, Drain};
 use std::{
     env,
     io::{BufReader, BufWriter, Read, Write},
-    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    time::Duration,
 };
+#[cfg(unix)]
+use {kvs::server::Shutdown, std::thread};
 
 fn main() -> kvs::Result<()> {
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let drain = slog_async::Async::new(drain).build().fuse();
-
-    let server = slog::Logger::root(drain, o!());
+    // before any thread is spawned, so all of them inherit it
+    #[cfg(unix)]
+    block_signals();
 
     let matches = command!()
         .about("Set IP address, port and which engine to run")
         .args(&[
+            Arg::new("config")
+                .long("config")
+                .value_name("PATH")
+                .help("TOML or JSON file of settings overridden by flags")
+                .value_parser(clap::value_parser!(PathBuf)),
             Arg::new("ip_port")
                 .long("addr")
                 .value_name("IP-PORT")
                 .help("IP address and port")
                 .required(false),
+            Arg::new("resp_addr")
+                .long("resp-addr")
+                .value_name("IP-PORT")
+                .help("IP address and port to serve Redis clients with RESP2")
+                .required(false),
+            Arg::new("http_addr")
+                .long("http-addr")
+                .value_name("IP-PORT")
+                .help("IP address and port to serve the HTTP/JSON gateway")
+                .required(false),
             Arg::new("engine_name")
                 .long("engine")
                 .value_name("ENGINE-NAME")
                 .help("Name of used engine")
                 .required(false),
+            Arg::new("data_dir")
+                .long("data-dir")
+                .value_name("PATH")
+                .help("Directory of data, `./.kv_data` by default")
+                .value_parser(clap::value_parser!(PathBuf)),
+            Arg::new("threads")
+                .long("threads")
+                .value_name("N")
+                .help("Threads to serve clients, one per CPU by default")
+                .value_parser(
+                    clap::builder::RangedU64ValueParser::<usize>::new()
+                        .range(1..),
+                ),
+            Arg::new("pool")
+                .long("pool")
+                .value_name("POOL")
+                .help("Thread pool to serve clients, `naive` by default")
+                .value_parser(["naive", "shared", "rayon"]),
+            Arg::new("log_level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Least level of logs printed, `info` by default")
+                .value_parser([
+                    "critical", "error", "warn", "info", "debug", "trace",
+                ]),
+            Arg::new("migrate")
+                .long("migrate")
+                .help("Move data persisted with another engine into it")
+                .action(ArgAction::SetTrue),
+            Arg::new("compaction_threshold")
+                .long("compaction-threshold")
+                .value_name("BYTES")
+                .help("Stale bytes of data to compact, `kvs` engine only")
+                .value_parser(clap::value_parser!(u64)),
+            Arg::new("sync_mode")
+                .long("sync-mode")
+                .value_name("MODE")
+                .help("Whether to sync each write to disk, `kvs` engine only")
+                .value_parser(["never", "always"]),
+            Arg::new("codec")
+                .long("codec")
+                .value_name("CODEC")
+                .help("Codec to compress values, `kvs` engine only")
+                .value_parser(["none", "lz4"]),
+            Arg::new("compress_threshold")
+                .long("compress-threshold")
+                .value_name("BYTES")
+                .help("Least size of values to compress, `kvs` engine only")
+                .value_parser(clap::value_parser!(usize)),
+            Arg::new("index_mode")
+                .long("index-mode")
+                .value_name("MODE")
+                .help("Where to keep the key index, `kvs` engine only")
+                .value_parser(["memory", "disk"]),
+            Arg::new("max_file_size")
+                .long("max-file-size")
+                .value_name("BYTES")
+                .help("Size of data files to seal, `kvs` engine only")
+                .value_parser(clap::value_parser!(u64)),
+            Arg::new("cache_size")
+                .long("cache-size")
+                .value_name("BYTES")
+                .help("Bytes of values to cache, `kvs` engine only")
+                .value_parser(clap::value_parser!(usize)),
+            Arg::new("key_file")
+                .long("key-file")
+                .value_name("PATH")
+                .help("File of the key to encrypt data, `kvs` engine only")
+                .required(false),
+            Arg::new("old_key_file")
+                .long("old-key-file")
+                .value_name("PATH")
+                .help("File of a key to decrypt old data, `kvs` engine only")
+                .action(ArgAction::Append)
+                .required(false),
+            Arg::new("tls_cert")
+                .long("tls-cert")
+                .value_name("PATH")
+                .help("PEM certificate chain to serve clients over TLS")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls_key"),
+            Arg::new("tls_key")
+                .long("tls-key")
+                .value_name("PATH")
+                .help("PEM private key of the TLS certificate")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls_cert"),
+            Arg::new("tls_client_ca")
+                .long("tls-client-ca")
+                .value_name("PATH")
+                .help("PEM certificates of CAs required to sign clients")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("tls_cert"),
+            Arg::new("auth_file")
+                .long("auth-file")
+                .value_name("PATH")
+                .help("File of users required to authenticate, with ACLs")
+                .value_parser(clap::value_parser!(PathBuf)),
+            Arg::new("drain_timeout")
+                .long("drain-timeout")
+                .value_name("SECONDS")
+                .help("Time for connections to finish on shutdown, 10 seconds")
+                .value_parser(clap::value_parser!(u64)),
+            Arg::new("hash_password")
+                .long("hash-password")
+                .help("Print the hash for the auth file of a password on stdin")
+                .action(ArgAction::SetTrue),
         ])
+        .after_help(format!(
+            "The config file is JSON if its name ends with `.json`, or TOML \
+            otherwise. It may set `addr`, `resp-addr`, `http-addr`, \
+            `engine`, `data-dir`, `threads`, `pool`, `log-level`, \
+            `compaction-threshold`, `sync-mode`, `codec`, \
+            `compress-threshold`, `index-mode`, `max-file-size`, \
+            `cache-size` and `drain-timeout`, like the flags of the same \
+            names.\n\n\
+            The key is read from environment variable {KEY_ENV} if \
+            `--key-file` is not given.\n\n\
+            TLS is served at the addresses of `--addr` and `--resp-addr`, \
+            but not of `--http-addr`, which can not be used with \
+            `--auth-file`.\n\n\
+            Each line of the auth file is `NAME PASSWORD-HASH RULE...`, \
+            where the hash is printed by `--hash-password` and a rule like \
+            `read:PREFIX`, `write@NAMESPACE:PREFIX` or `admin@*:PREFIX` \
+            grants access to keys starting with the prefix.\n\n\
+            SIGINT or SIGTERM shuts the server down gracefully, and another \
+            one exits at once."
+        ))
         .get_matches();
-    let addr = matches
-        .get_one::<String>("ip_port")
-        .map_or(String::from("127.0.0.1:4000"), |x| x.clone());
-    let engine = matches
-        .get_one::<String>("engine_name")
-        .map_or(String::from("kvs"), |x| x.clone());
+    if matches.get_flag("hash_password") {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        println!("{}", kvs::auth::hash_password(password)?);
+        return Ok(());
+    }
+    let config = match matches.get_one::<PathBuf>("config") {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    }
+    .merge(&matches);
+    if config.threads == Some(0) {
+        return Err(kvs::Error::Message(
+            "at least 1 thread is needed to serve clients".into(),
+        ));
+    }
+
+    let level = match config.log_level.as_deref() {
+        Some(level) => level.parse().map_err(|_| {
+            kvs::Error::Message(format!("invalid log level {level}"))
+        })?,
+        None => slog::Level::Info,
+    };
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    // the guard flushes logs when main returns
+    let (drain, _guard) = slog_async::Async::new(drain).build_with_guard();
+    let drain = slog::LevelFilter::new(drain.fuse(), level).fuse();
+
+    let server = slog::Logger::root(drain, o!());
+
+    let engine = config.engine.clone().unwrap_or(String::from("kvs"));
+    let migrate = matches.get_flag("migrate");
     let version = std::env!("CARGO_PKG_VERSION");
 
-    let path = env::current_dir()?.join(".kv_data");
-    let cpus = num_cpus::get();
+    let path = match &config.data_dir {
+        Some(path) => path.clone(),
+        None => env::current_dir()?.join(".kv_data"),
+    };
 
     std::fs::create_dir_all(&path)?;
+    // also used to read or write data of `kvs` on migration
+    let options = store_options(&matches, &config)?;
     match engine.as_str() {
         "kvs" => {
-            identify_engine(path.as_path(), "kvs", &server)?;
+            identify_engine(path.as_path(), "kvs", migrate, &options, &server)?;
             info!(server, "version v{version} with engine {engine}.");
-            KvsServer {
-                logger: server,
-                store: KvStore::open(path.clone())?,
-                pool: NaiveThreadPool::new(cpus)?,
-            }
-            .run(addr)
+            let store = KvStore::open_with(path.clone(), options)?;
+            start("kvs", store, &matches, &config, &server)
         }
         "sled" => {
-            identify_engine(path.as_path(), "sled", &server)?;
-            info!(server, "version v{version} with engine {engine}.");
-            KvsServer {
-                logger: server,
-                store: SledStore::open(path.clone())?,
-                pool: NaiveThreadPool::new(cpus)?,
+            // a key may decrypt data of `kvs` to migrate
+            if matches.contains_id("key_file") && !migrate {
+                error!(server, "encryption is only supported by engine kvs");
+                std::process::exit(1);
+            }
+            if config.compaction_threshold.is_some()
+                || config.sync_mode.is_some()
+                || config.codec.is_some()
+                || config.compress_threshold.is_some()
+                || config.index_mode.is_some()
+                || config.max_file_size.is_some()
+                || config.cache_size.is_some()
+            {
+                error!(server, "engine options are only supported by kvs");
+                std::process::exit(1);
             }
-            .run(addr)
+            identify_engine(
+                path.as_path(),
+                "sled",
+                migrate,
+                &options,
+                &server,
+            )?;
+            info!(server, "version v{version} with engine {engine}.");
+            let store = SledStore::open(path.clone())?;
+            start("sled", store, &matches, &config, &server)
         }
         _ => {
             error!(server, "select a nonexistent engine");
@@ -74,99 +267,274 @@ fn main() -> kvs::Result<()> {
     }
 }
 
+// settings read from a config file, with names of the flags
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    addr: Option<String>,
+    resp_addr: Option<String>,
+    http_addr: Option<String>,
+    engine: Option<String>,
+    data_dir: Option<PathBuf>,
+    threads: Option<usize>,
+    pool: Option<String>,
+    log_level: Option<String>,
+    compaction_threshold: Option<u64>,
+    sync_mode: Option<String>,
+    codec: Option<String>,
+    compress_threshold: Option<usize>,
+    index_mode: Option<String>,
+    max_file_size: Option<u64>,
+    cache_size: Option<usize>,
+    drain_timeout: Option<u64>,
+}
+
+impl Config {
+    fn from_file(path: &Path) -> kvs::Result<Config> {
+        let text = std::fs::read_to_string(path)?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            return Ok(serde_json::from_str(&text)?);
+        }
+        toml::from_str(&text).map_err(|e| {
+            kvs::Error::Message(format!(
+                "invalid config {}: {e}",
+                path.display()
+            ))
+        })
+    }
+
+    // override settings by flags given in `matches`
+    fn merge(mut self, matches: &ArgMatches) -> Config {
+        fn set<T: Clone + Send + Sync + 'static>(
+            setting: &mut Option<T>,
+            matches: &ArgMatches,
+            id: &str,
+        ) {
+            if let Some(value) = matches.get_one::<T>(id) {
+                *setting = Some(value.clone());
+            }
+        }
+        set(&mut self.addr, matches, "ip_port");
+        set(&mut self.resp_addr, matches, "resp_addr");
+        set(&mut self.http_addr, matches, "http_addr");
+        set(&mut self.engine, matches, "engine_name");
+        set(&mut self.data_dir, matches, "data_dir");
+        set(&mut self.threads, matches, "threads");
+        set(&mut self.pool, matches, "pool");
+        set(&mut self.log_level, matches, "log_level");
+        set(
+            &mut self.compaction_threshold,
+            matches,
+            "compaction_threshold",
+        );
+        set(&mut self.sync_mode, matches, "sync_mode");
+        set(&mut self.codec, matches, "codec");
+        set(&mut self.compress_threshold, matches, "compress_threshold");
+        set(&mut self.index_mode, matches, "index_mode");
+        set(&mut self.max_file_size, matches, "max_file_size");
+        set(&mut self.cache_size, matches, "cache_size");
+        set(&mut self.drain_timeout, matches, "drain_timeout");
+        self
+    }
+}
+
+const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+// options of the `kvs` engine given by flags and `config`
+fn store_options(
+    matches: &ArgMatches,
+    config: &Config,
+) -> kvs::Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new();
+    if let Some(key_path) = matches.get_one::<String>("key_file") {
+        options = options.encryption_key(EncryptionKey::from_file(key_path)?);
+    } else if env::var_os(KEY_ENV).is_some() {
+        options = options.encryption_key(EncryptionKey::from_env(KEY_ENV)?);
+    }
+    for key_path in matches
+        .get_many::<String>("old_key_file")
+        .into_iter()
+        .flatten()
+    {
+        options = options.decryption_key(EncryptionKey::from_file(key_path)?);
+    }
+    if let Some(bytes) = config.compaction_threshold {
+        options = options.compaction_threshold(bytes);
+    }
+    match config.sync_mode.as_deref() {
+        Some("always") => options = options.sync_mode(SyncMode::Always),
+        Some("never") | None => {}
+        Some(mode) => {
+            return Err(kvs::Error::Message(format!(
+                "select a nonexistent sync mode {mode}"
+            )))
+        }
+    }
+    match config.codec.as_deref() {
+        Some("lz4") => options = options.codec(Codec::Lz4),
+        Some("none") | None => {}
+        Some(codec) => {
+            return Err(kvs::Error::Message(format!(
+                "select a nonexistent codec {codec}"
+            )))
+        }
+    }
+    if let Some(bytes) = config.compress_threshold {
+        options = options.compress_threshold(bytes);
+    }
+    match config.index_mode.as_deref() {
+        Some("disk") => options = options.index_mode(IndexMode::Disk),
+        Some("memory") | None => {}
+        Some(mode) => {
+            return Err(kvs::Error::Message(format!(
+                "select a nonexistent index mode {mode}"
+            )))
+        }
+    }
+    if let Some(bytes) = config.max_file_size {
+        options = options.max_file_size(bytes);
+    }
+    if let Some(bytes) = config.cache_size {
+        options = options.cache_size(bytes);
+    }
+    Ok(options)
+}
+
 fn identify_engine(
     path: &std::path::Path,
     current: &str,
+    migrate: bool,
+    options: &KvStoreOptions,
     logger: &slog::Logger,
 ) -> kvs::Result<()> {
-    let path = path.join("identity");
-    if let Ok(file) = std::fs::File::open(&path) {
+    let id_path = path.join("identity");
+    if let Ok(file) = std::fs::File::open(&id_path) {
         let mut id = String::new();
         let mut id_reader = BufReader::new(file);
         id_reader.read_to_string(&mut id)?;
-        if id != current {
+        if id != current && migrate {
+            info!(logger, "migrate data from engine {id} to {current}.");
+            let count = kvs::migrate(path, &id, current, options)?;
+            info!(logger, "{count} keys migrated.");
+        } else if id != current {
             error!(
                 logger,
                 "select `{current}` as engine, but pervious data is persisted \
-                with a different engine {id}"
+                with a different engine {id}, use `--migrate` to move it"
             );
             std::process::exit(1);
         }
     } else {
-        let mut id_writer = BufWriter::new(std::fs::File::create_new(path)?);
+        let mut id_writer = BufWriter::new(std::fs::File::create_new(id_path)?);
         id_writer.write_all(current.as_bytes())?;
     }
     Ok(())
 }
 
-struct KvsServer<E: KvsEngine, P: ThreadPool> {
-    logger: slog::Logger,
+// serve `store` with the thread pool selected by `config`
+fn start<E: KvsEngine>(
+    engine: &'static str,
     store: E,
-    pool: P,
-}
-
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
-    fn run<A>(&mut self, addr: A) -> kvs::Result<()>
-    where
-        A: std::net::ToSocketAddrs + std::fmt::Display,
-    {
-        let listener = TcpListener::bind(&addr).unwrap_or_else(|_| {
-            panic!("unable to create TCP listener at {addr}")
-        });
-        info!(self.logger, "server starts at {addr}.");
-
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let store = self.store.clone();
-                    let logger = self.logger.clone();
-                    self.pool.spawn(move || {
-                        if let Err(e) = process(store, &logger, stream) {
-                            error!(logger, "failed to serve client: {e}");
-                        }
-                    });
-                }
-                Err(e) => error!(self.logger, "failed at connection: {e}"),
-            }
+    matches: &ArgMatches,
+    config: &Config,
+    logger: &slog::Logger,
+) -> kvs::Result<()> {
+    let threads = config.threads.unwrap_or_else(num_cpus::get);
+    match config.pool.as_deref() {
+        Some("naive") | None => {
+            let pool = NaiveThreadPool::new(threads)?;
+            let server = KvsServer::new(engine, store, pool, logger.clone());
+            serve(server, matches, config, logger)
+        }
+        Some("shared") => {
+            let pool = SharedQueueThreadPool::new(threads)?;
+            let server = KvsServer::new(engine, store, pool, logger.clone());
+            serve(server, matches, config, logger)
+        }
+        Some("rayon") => {
+            let pool = RayonThreadPool::new(threads)?;
+            let server = KvsServer::new(engine, store, pool, logger.clone());
+            serve(server, matches, config, logger)
+        }
+        Some(pool) => {
+            error!(logger, "select a nonexistent thread pool {pool}");
+            std::process::exit(1)
         }
-
-        Ok(())
     }
 }
 
-fn process<E: KvsEngine>(
-    store: E,
+// configure `server` by `matches` and `config`, and run it until a signal
+// shuts it down
+fn serve<E: KvsEngine, P: ThreadPool + Sync>(
+    mut server: KvsServer<E, P>,
+    matches: &ArgMatches,
+    config: &Config,
     logger: &slog::Logger,
-    stream: TcpStream,
 ) -> kvs::Result<()> {
-    let reader = Deserializer::from_reader(BufReader::new(&stream));
-    let mut writer = BufWriter::new(&stream);
-
-    for request in reader.into_iter::<Request>() {
-        let mut response = String::new();
-        match request? {
-            Request::Set { key, value } => {
-                store.set(key, value)?;
-            }
-            Request::Get { key } => {
-                let value = store.get(key)?;
-                response = match value {
-                    Some(v) => v,
-                    None => "Key not found".into(),
-                };
-            }
-            Request::Remove { key } => {
-                if store.remove(key).is_err() {
-                    response = "Key not found".into()
-                }
-            }
-        }
+    if let Some(addr) = &config.resp_addr {
+        server = server.resp_addr(addr);
+    }
+    if let Some(addr) = &config.http_addr {
+        server = server.http_addr(addr);
+    }
+    if let Some(cert) = matches.get_one::<PathBuf>("tls_cert") {
+        server = server.tls(tls::server_config(
+            cert,
+            matches.get_one::<PathBuf>("tls_key").unwrap(),
+            matches
+                .get_one::<PathBuf>("tls_client_ca")
+                .map(PathBuf::as_path),
+        )?);
+    }
+    if let Some(path) = matches.get_one::<PathBuf>("auth_file") {
+        server = server.credentials(Credentials::from_file(path)?);
+    }
+    let timeout = config.drain_timeout.unwrap_or(10);
+    server = server.drain_timeout(Duration::from_secs(timeout));
 
-        let response = Response::Status(response);
-        serde_json::to_writer(&mut writer, &response)?;
-        writer.flush()?;
-        debug!(logger, "send response {:?}", response);
+    #[cfg(unix)]
+    handle_signals(server.shutdown_handle(), logger.clone());
+    server.run(config.addr.as_deref().unwrap_or("127.0.0.1:4000"))
+}
+
+// signals to shut down the server
+#[cfg(unix)]
+fn shutdown_signals() -> libc::sigset_t {
+    // SAFETY: the set is initialized by `sigemptyset` before it is filled
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        set
     }
+}
 
-    Ok(())
+// block shutdown signals in this thread and threads spawned by it, so they
+// are only taken by `sigwait`
+#[cfg(unix)]
+fn block_signals() {
+    let set = shutdown_signals();
+    // SAFETY: `set` is initialized, and the old mask is not asked for
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+}
+
+// shut down gracefully on a signal, and exit at once on another
+#[cfg(unix)]
+fn handle_signals(shutdown: Shutdown, logger: slog::Logger) {
+    thread::spawn(move || {
+        let set = shutdown_signals();
+        let mut signal = 0;
+        // SAFETY: `set` is initialized, and `signal` is valid to write
+        while unsafe { libc::sigwait(&set, &mut signal) } == 0 {
+            if shutdown.is_shutdown() {
+                error!(logger, "exit on signal {signal} during shutdown.");
+                std::process::exit(1);
+            }
+            info!(logger, "shut down on signal {signal}.");
+            shutdown.shutdown();
+        }
+    });
 }
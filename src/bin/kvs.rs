@@ -1,9 +1,33 @@
-use clap::{arg, command, Command};
-use kvs::{KvStore, KvsEngine};
+use chrono::DateTime;
+use clap::{arg, command, ArgAction, ArgMatches, Command};
+use kvs::{
+    EncryptionKey, EntryInfo, KvStore, KvStoreOptions, KvsEngine, SledStore,
+    VerifyReport,
+};
+use std::{
+    io::{self, BufReader, BufWriter},
+    ops::Bound,
+    path::{Path, PathBuf},
+};
 
 fn main() -> kvs::Result<()> {
+    let data_dir_arg =
+        arg!(--"data-dir" <PATH> "Directory of the data").required(false);
     // create command line interface by using builder API in clap
     let matches = command!() // requires `cargo` feature
+        .args(&[
+            arg!(--"key-file" <PATH> "File of the key to encrypt data")
+                .required(false)
+                .global(true),
+            arg!(--"old-key-file" <PATH> "File of a key to decrypt old data")
+                .required(false)
+                .action(ArgAction::Append)
+                .global(true),
+        ])
+        .after_help(format!(
+            "The key is read from environment variable {KEY_ENV} if \
+            `--key-file` is not given."
+        ))
         .subcommands(&[
             Command::new("set")
                 .about("Set the value of a string key to a string")
@@ -17,22 +41,67 @@ fn main() -> kvs::Result<()> {
             Command::new("rm")
                 .about("Remove a given string key")
                 .arg(arg!(<KEY> "A string key")),
+            Command::new("dump")
+                .about("Write all key-value pairs to stdout as JSON Lines"),
+            Command::new("load")
+                .about("Read key-value pairs in JSON Lines from stdin"),
+            Command::new("migrate")
+                .about("Move persisted data into another engine")
+                .args(&[
+                    arg!(--from <ENGINE> "Engine which persisted the data"),
+                    arg!(--to <ENGINE> "Engine to move the data into"),
+                    data_dir_arg.clone(),
+                ]),
+            Command::new("verify")
+                .about("Check data files of the `kvs` engine")
+                .after_help(
+                    "Exit with 0 if no problem is found, 2 if data files \
+                    are damaged and 1 if the check itself fails.",
+                )
+                .arg(data_dir_arg.clone()),
+            Command::new("repair")
+                .about("Rewrite readable data and quarantine damaged files")
+                .after_help(
+                    "Exit with 0 if data files are healthy or repaired, \
+                    and 1 if the repair fails.",
+                )
+                .arg(data_dir_arg.clone()),
+            Command::new("upgrade")
+                .about("Rewrite data files of older versions of `kvs`")
+                .after_help(
+                    "Data files written before file headers are rewritten in \
+                    the current format, encrypted if a key is given.",
+                )
+                .arg(data_dir_arg.clone()),
+            Command::new("inspect")
+                .about("Print entries in data files of the `kvs` engine")
+                .args(&[
+                    arg!([FILE] "Data file to print, such as `data-1`")
+                        .required_unless_present("key"),
+                    arg!(
+                        --key <KEY>
+                        "Print all versions of a key and range tombstones of it"
+                    )
+                    .required(false),
+                    data_dir_arg.clone(),
+                ]),
         ])
         .get_matches();
 
     let path = std::env::current_dir()?.join(".kv_data");
+    let options = store_options(&matches)?;
     match matches.subcommand() {
         Some(("set", sub_m)) => {
             let key = sub_m.get_one::<String>("KEY").unwrap();
             let value = sub_m.get_one::<String>("VALUE").unwrap();
 
-            let store = KvStore::open(path)?;
+            let store = KvStore::open_with(path, options)?;
             store.set(key.clone(), value.clone())
         }
         Some(("get", sub_m)) => {
             let key = sub_m.get_one::<String>("KEY").unwrap();
 
-            let store = KvStore::open(path)?;
+            let store = KvStore::open_with(path, options)?;
             let value = store.get(key.clone())?;
             match value {
                 Some(v) => println!("{v}"),
@@ -43,13 +112,165 @@ fn main() -> kvs::Result<()> {
         Some(("rm", sub_m)) => {
             let key = sub_m.get_one::<String>("KEY").unwrap();
 
-            let store = KvStore::open(path)?;
+            let store = KvStore::open_with(path, options)?;
             if store.remove(key.clone()).is_err() {
                 println!("Key not found");
                 std::process::exit(1);
             }
             Ok(())
         }
+        Some(("dump", _)) => {
+            let stdout = BufWriter::new(io::stdout().lock());
+            match identity(&path).as_str() {
+                "sled" => kvs::dump(&SledStore::open(path)?, stdout),
+                _ => kvs::dump(&KvStore::open_with(path, options)?, stdout),
+            }?;
+            Ok(())
+        }
+        Some(("load", _)) => {
+            let stdin = BufReader::new(io::stdin().lock());
+            match identity(&path).as_str() {
+                "sled" => kvs::load(&SledStore::open(path)?, stdin),
+                _ => kvs::load(&KvStore::open_with(path, options)?, stdin),
+            }?;
+            Ok(())
+        }
+        Some(("migrate", sub_m)) => {
+            let from = sub_m.get_one::<String>("from").unwrap();
+            let to = sub_m.get_one::<String>("to").unwrap();
+            let path = sub_m
+                .get_one::<String>("data-dir")
+                .map_or(path, PathBuf::from);
+
+            let count = kvs::migrate(&path, from, to, &options)?;
+            println!("{count} keys migrated from {from} to {to}");
+            Ok(())
+        }
+        Some(("verify", sub_m)) => {
+            let path = sub_m
+                .get_one::<String>("data-dir")
+                .map_or(path, PathBuf::from);
+
+            let report = KvStore::verify(path, &options)?;
+            print_report(&report);
+            if !report.is_healthy() {
+                std::process::exit(2);
+            }
+            Ok(())
+        }
+        Some(("repair", sub_m)) => {
+            let path = sub_m
+                .get_one::<String>("data-dir")
+                .map_or(path, PathBuf::from);
+
+            let report = KvStore::repair(&path, &options)?;
+            print_report(&report);
+            for file_id in &report.quarantined {
+                println!(
+                    "data-{file_id} is moved into {}",
+                    path.join("quarantine").display()
+                );
+            }
+            Ok(())
+        }
+        Some(("upgrade", sub_m)) => {
+            let path = sub_m
+                .get_one::<String>("data-dir")
+                .map_or(path, PathBuf::from);
+
+            let count = KvStore::upgrade(path, &options)?;
+            println!("{count} keys upgraded");
+            Ok(())
+        }
+        Some(("inspect", sub_m)) => {
+            let path = sub_m
+                .get_one::<String>("data-dir")
+                .map_or(path, PathBuf::from);
+            let file_id = match sub_m.get_one::<String>("FILE") {
+                Some(file) => {
+                    Some(file.trim_start_matches("data-").parse::<u64>()?)
+                }
+                None => None,
+            };
+            let key = sub_m.get_one::<String>("key");
+
+            // the history of a key includes range tombstones removing it
+            let of_key = |e: &EntryInfo, key: &String| match &e.range_end {
+                Some(Bound::Excluded(end)) => e.key <= *key && key < end,
+                Some(_) => e.key <= *key,
+                None => e.key == *key,
+            };
+            let damages = KvStore::inspect(path, &options, |e| {
+                if file_id.is_some_and(|id| id != e.file_id)
+                    || key.is_some_and(|key| !of_key(&e, key))
+                {
+                    return;
+                }
+                let time = DateTime::from_timestamp(e.timestamp, 0)
+                    .map_or(e.timestamp.to_string(), |t| t.to_rfc3339());
+                let keyspace = e.keyspace.map_or(String::new(), |name| {
+                    format!("keyspace={name:?} ")
+                });
+                let range_end = match e.range_end {
+                    Some(Bound::Excluded(end)) => format!(" range_end={end:?}"),
+                    Some(_) => " range_end=..".to_owned(),
+                    None => String::new(),
+                };
+                println!(
+                    "data-{} offset={} size={} seq={} timestamp={} {}key={:?} \
+                    value_len={} tombstone={}{} live={}",
+                    e.file_id,
+                    e.offset,
+                    e.size,
+                    e.seq,
+                    time,
+                    keyspace,
+                    e.key,
+                    e.value_len,
+                    e.tombstone,
+                    range_end,
+                    e.live
+                );
+            })?;
+            for damage in damages {
+                eprintln!("{damage}");
+            }
+            Ok(())
+        }
         _ => panic!(),
     }
 }
+
+const KEY_ENV: &str = "KVS_ENCRYPTION_KEY";
+
+fn store_options(matches: &ArgMatches) -> kvs::Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::new();
+    if let Some(path) = matches.get_one::<String>("key-file") {
+        options = options.encryption_key(EncryptionKey::from_file(path)?);
+    } else if std::env::var_os(KEY_ENV).is_some() {
+        options = options.encryption_key(EncryptionKey::from_env(KEY_ENV)?);
+    }
+    for path in matches
+        .get_many::<String>("old-key-file")
+        .into_iter()
+        .flatten()
+    {
+        options = options.decryption_key(EncryptionKey::from_file(path)?);
+    }
+    Ok(options)
+}
+
+// name of the engine which persisted data in `path`, `kvs` by default
+fn identity(path: &Path) -> String {
+    std::fs::read_to_string(path.join("identity")).unwrap_or("kvs".into())
+}
+
+fn print_report(report: &VerifyReport) {
+    for damage in &report.damages {
+        println!("{damage}");
+    }
+    println!(
+        "{} files, {} entries, {} unreachable entries, {} garbage bytes",
+        report.files, report.entries, report.unreachable, report.garbage_bytes
+    );
+}
@@ -0,0 +1,304 @@
+use crate::{
+    common::{binary, Request, Response, Tagged, PROTOCOL_VERSION},
+    tls::Stream,
+    Error, Result,
+};
+use rustls::ClientConfig;
+use serde::Deserialize;
+use serde_json::de::{Deserializer, IoRead};
+use std::{
+    collections::VecDeque,
+    io::{BufReader, BufWriter, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+};
+
+// bytes of requests a pipeline leaves unanswered, which fit in the buffers
+// of both sockets, so writing them never waits for the server
+const MAX_UNANSWERED_LEN: usize = 64 << 10;
+
+/// Encoding of messages between client and server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Framed binary messages of [`binary`]
+    Binary,
+    /// JSON messages
+    Json,
+}
+
+/// Options for connecting a [`KvsClient`].
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use kvs::client::{ClientOptions, KvsClient, Protocol};
+/// # use std::path::Path;
+/// let tls = kvs::tls::client_config(Path::new("ca.pem"), None).unwrap();
+/// let options = ClientOptions::new()
+///     .protocol(Protocol::Json)
+///     .tls(tls, "localhost");
+/// let client = KvsClient::connect_with("127.0.0.1:4000", options).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientOptions {
+    protocol: Protocol,
+    tls: Option<(Arc<ClientConfig>, String)>,
+    // user and password to authenticate as
+    auth: Option<(String, String)>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            protocol: Protocol::Binary,
+            tls: None,
+            auth: None,
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Create default options, speaking the binary protocol in plaintext.
+    pub fn new() -> ClientOptions {
+        ClientOptions::default()
+    }
+
+    /// Set the encoding of messages.
+    pub fn protocol(mut self, protocol: Protocol) -> ClientOptions {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Connect over TLS with `config`, see [`crate::tls::client_config`],
+    /// to the server named `server_name` in its certificate.
+    pub fn tls(
+        mut self,
+        config: Arc<ClientConfig>,
+        server_name: impl Into<String>,
+    ) -> ClientOptions {
+        self.tls = Some((config, server_name.into()));
+        self
+    }
+
+    /// Authenticate as `user` with `password` after connecting.
+    pub fn auth(
+        mut self,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> ClientOptions {
+        self.auth = Some((user.into(), password.into()));
+        self
+    }
+}
+
+// responses from the server in the chosen protocol
+enum Responses {
+    Json(Deserializer<IoRead<BufReader<Stream>>>),
+    Binary(BufReader<Stream>),
+}
+
+/// Connection to a `kvs-server`.
+///
+/// Requests are tagged with increasing IDs, so responses are checked to
+/// belong to them.
+pub struct KvsClient {
+    reader: Responses,
+    writer: BufWriter<Stream>,
+    binary: bool,
+    // ID of the last request sent
+    last_id: u64,
+    // IDs of requests sent but not answered, oldest first
+    pending: VecDeque<u64>,
+    features: Vec<String>,
+}
+
+impl KvsClient {
+    /// Connect to the server at `addr` with default options.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::connect_with(addr, ClientOptions::new())
+    }
+
+    /// Connect to the server at `addr`, exchange hellos with it, and
+    /// authenticate if a user is given.
+    ///
+    /// It fails if the server speaks another protocol version or rejects the
+    /// user.
+    pub fn connect_with(
+        addr: impl ToSocketAddrs,
+        options: ClientOptions,
+    ) -> Result<KvsClient> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let stream = match options.tls {
+            Some((config, server_name)) => {
+                Stream::connect(stream, config, &server_name)?
+            }
+            None => Stream::plain(stream),
+        };
+        let mut reader = BufReader::new(stream.clone());
+        let mut writer = BufWriter::new(stream);
+        let protocol = options.protocol;
+        let reader = match protocol {
+            Protocol::Binary => {
+                writer.write_all(&binary::MAGIC)?;
+                writer.flush()?;
+                let mut magic = [0; 4];
+                reader.read_exact(&mut magic)?;
+                if magic != binary::MAGIC {
+                    return Err(Error::Message(
+                        "server does not support the binary protocol".into(),
+                    ));
+                }
+                Responses::Binary(reader)
+            }
+            Protocol::Json => {
+                Responses::Json(Deserializer::from_reader(reader))
+            }
+        };
+
+        let mut client = KvsClient {
+            reader,
+            writer,
+            binary: protocol == Protocol::Binary,
+            last_id: 0,
+            pending: VecDeque::new(),
+            features: Vec::new(),
+        };
+        let encoding = match protocol {
+            Protocol::Binary => "encoding:binary",
+            Protocol::Json => "encoding:json",
+        };
+        let hello = Request::Hello {
+            version: PROTOCOL_VERSION,
+            features: vec![encoding.to_owned()],
+        };
+        match client.request(&hello)? {
+            Response::Hello { version, .. } if version != PROTOCOL_VERSION => {
+                return Err(Error::Message(format!(
+                    "server speaks protocol version {version}, but client \
+                    speaks {PROTOCOL_VERSION}"
+                )))
+            }
+            Response::Hello { features, .. } => {
+                client.features = features;
+            }
+            Response::Error { message, .. } => {
+                return Err(Error::Message(message))
+            }
+            response => return Err(unexpected(response)),
+        }
+
+        if let Some((user, password)) = options.auth {
+            match client.request(&Request::Auth { user, password })? {
+                Response::Ok => {}
+                Response::Error { message, .. } => {
+                    return Err(Error::Message(message))
+                }
+                response => return Err(unexpected(response)),
+            }
+        }
+        Ok(client)
+    }
+
+    /// Features advertised by the server, like `engine:kvs`.
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+
+    /// Send `request` and wait for its response.
+    ///
+    /// Failed requests are answered with [`Response::Error`] rather than an
+    /// error, which is kept for failures of the connection.
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        self.send(request)?;
+        self.writer.flush()?;
+        self.receive()
+    }
+
+    /// Send `requests` without waiting for responses, keeping at most
+    /// `depth` of them unanswered, and return responses in the same order.
+    ///
+    /// Unanswered requests are also kept within 64 KiB, or a single request.
+    /// Requests other than `Watch` may be pipelined.
+    pub fn pipeline<'a>(
+        &mut self,
+        requests: impl IntoIterator<Item = &'a Request>,
+        depth: usize,
+    ) -> Result<Vec<Response>> {
+        let depth = depth.max(1);
+        let mut responses = Vec::new();
+        // sizes of unanswered requests, oldest first, and their sum
+        let mut sizes = VecDeque::new();
+        let mut unanswered = 0;
+        for request in requests {
+            let buf = self.encode(self.last_id + 1, request)?;
+            // the server may block on writing responses nobody reads, and
+            // stop reading requests, so only write what socket buffers hold
+            while !sizes.is_empty()
+                && (sizes.len() >= depth
+                    || unanswered + buf.len() > MAX_UNANSWERED_LEN)
+            {
+                self.writer.flush()?;
+                responses.push(self.receive()?);
+                unanswered -= sizes.pop_front().unwrap();
+            }
+            unanswered += buf.len();
+            sizes.push_back(buf.len());
+            self.write(&buf)?;
+        }
+        self.writer.flush()?;
+        while !self.pending.is_empty() {
+            responses.push(self.receive()?);
+        }
+        Ok(responses)
+    }
+
+    /// Receive the next response to the oldest unanswered request, or
+    /// another event of a watch after all are answered.
+    pub fn receive(&mut self) -> Result<Response> {
+        let Tagged { id, body } = match &mut self.reader {
+            Responses::Json(reader) => Tagged::deserialize(reader)?,
+            Responses::Binary(reader) => binary::read_response(reader)?
+                .ok_or_else(|| {
+                    Error::Message("connection closed by server".into())
+                })?,
+        };
+        let expected = self.pending.pop_front().unwrap_or(self.last_id);
+        if id != expected {
+            return Err(Error::Message(format!(
+                "response to request {id} is received, but {expected} is \
+                expected"
+            )));
+        }
+        Ok(body)
+    }
+
+    // write `request` with a new ID without flushing it
+    fn send(&mut self, request: &Request) -> Result<()> {
+        let buf = self.encode(self.last_id + 1, request)?;
+        self.write(&buf)
+    }
+
+    // encode `request` with ID `id` in the protocol of the connection
+    fn encode(&self, id: u64, request: &Request) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        if self.binary {
+            binary::write_request(&mut buf, id, request)?;
+        } else {
+            serde_json::to_writer(&mut buf, &Tagged { id, body: request })?;
+        }
+        Ok(buf)
+    }
+
+    // write a request encoded with the next ID without flushing it
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.write_all(buf)?;
+        self.last_id += 1;
+        self.pending.push_back(self.last_id);
+        Ok(())
+    }
+}
+
+fn unexpected(response: Response) -> Error {
+    Error::Message(format!("unexpected response {response:?}"))
+}
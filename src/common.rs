@@ -1,15 +1,169 @@
+use crate::{Error, Event};
 use serde::{Deserialize, Serialize};
 
+pub mod binary;
+pub mod resp;
+
+/// Version of the protocol spoken by this build, bumped on incompatible
+/// changes of [`Request`] or [`Response`].
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Message with an ID chosen by the client, which the server copies from a
+/// request to its responses, so a client may send many requests before
+/// reading any response.
+///
+/// An ID of 0 is left out of JSON, keeping it the same as a bare message.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Tagged<T> {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub id: u64,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+fn is_zero(id: &u64) -> bool {
+    *id == 0
+}
+
 /// Request from client.
+///
+/// Keys are in the keyspace named by `namespace`, or the default one if it is
+/// absent.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    /// Open a connection with the protocol version of the client and
+    /// features it uses, which the server answers with its own
+    ///
+    /// A connection without it is served as of the version of the server.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        features: Vec<String>,
+    },
+    /// Authenticate the connection as `user`, required before other
+    /// requests by servers advertising the `auth` feature
+    Auth { user: String, password: String },
+    Set {
+        key: String,
+        value: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Get {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    Remove {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Get values of `keys`, responding with them in the same order
+    MGet {
+        keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Set keys to values of `pairs` in order
+    MSet {
+        pairs: Vec<(String, String)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Add `delta` to an integer value and respond with the sum
+    Incr {
+        key: String,
+        delta: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Run `ops` in order as one transaction, responding with values got by
+    /// it
+    Transaction {
+        ops: Vec<TxOp>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+    /// Stream changes of keys starting with `prefix` until the connection
+    /// is closed
+    Watch {
+        prefix: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+    },
+}
+
+/// Operation of a [`Request::Transaction`].
+#[derive(Debug, Deserialize, Serialize)]
+pub enum TxOp {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Abort the transaction unless `key` has `value`, `None` for absent
+    Check {
+        key: String,
+        value: Option<String>,
+    },
 }
 
 /// Response from server.
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Response {
-    Status(String),
+    /// Protocol version and features of the server, such as
+    /// `engine:kvs`, `encoding:binary`, `tls` and `auth`
+    Hello { version: u32, features: Vec<String> },
+    /// Request is done without a result
+    Ok,
+    /// Value of a key, `None` if it does not exist
+    Value(Option<String>),
+    /// Values got by `MGet`, or by a transaction in the order of its `Get`
+    /// operations
+    Values(Vec<Option<String>>),
+    /// Sum of `Incr`
+    Integer(i64),
+    /// Change of a watched key
+    Event(Event),
+    /// Failure of a request, which leaves the connection usable
+    Error { code: ErrorCode, message: String },
+}
+
+/// Kind of a failed request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    /// The key to remove does not exist
+    NotFound,
+    /// The request cannot be done with its arguments or current values,
+    /// such as an invalid keyspace name or a failed check
+    Invalid,
+    /// The server fails to serve the request
+    Internal,
+    /// The protocol version of the client is not spoken by the server
+    Unsupported,
+    /// The connection is not authenticated, or its user has no access to
+    /// the keys
+    Denied,
+}
+
+impl Response {
+    /// Response of a failed request.
+    pub fn error(e: &Error) -> Response {
+        let code = match e {
+            Error::NonexistentKey => ErrorCode::NotFound,
+            Error::Message(_) | Error::ParseInt(_) => ErrorCode::Invalid,
+            Error::Denied(_) => ErrorCode::Denied,
+            _ => ErrorCode::Internal,
+        };
+        Response::Error {
+            code,
+            message: e.to_string(),
+        }
+    }
 }
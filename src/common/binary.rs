@@ -0,0 +1,426 @@
+//! Framed binary encoding of requests and responses.
+//!
+//! A client opts in by sending [`MAGIC`] as the first bytes of a connection,
+//! which the server echoes back. Every message is then a frame
+//! `| len | opcode | id | body |`, where `len` is a little-endian `u32`
+//! counting the rest of the frame up to 1 GiB, and `id` is the `u64` ID of
+//! the request.
+//! Strings are prefixed by their `u32` lengths, so they are never escaped.
+
+use crate::{
+    common::{ErrorCode, Request, Response, Tagged, TxOp},
+    Error, Event, Result,
+};
+use std::io::{self, Read, Write};
+
+/// First bytes sent by a client choosing the binary protocol.
+pub const MAGIC: [u8; 4] = *b"KVSB";
+
+// opcodes of requests
+const SET: u8 = 0x01;
+const GET: u8 = 0x02;
+const REMOVE: u8 = 0x03;
+const MGET: u8 = 0x04;
+const MSET: u8 = 0x05;
+const INCR: u8 = 0x06;
+const TRANSACTION: u8 = 0x07;
+const WATCH: u8 = 0x08;
+const HELLO: u8 = 0x09;
+const AUTH: u8 = 0x0a;
+// opcodes of responses
+const OK: u8 = 0x81;
+const VALUE: u8 = 0x82;
+const VALUES: u8 = 0x83;
+const INTEGER: u8 = 0x84;
+const EVENT: u8 = 0x85;
+const ERROR: u8 = 0x86;
+const HELLO_REPLY: u8 = 0x87;
+
+// bytes of the opcode and ID of a frame
+const HEADER_LEN: usize = 9;
+// longest frame accepted, so a peer cannot make the other side allocate
+// whatever a length claims
+const MAX_FRAME_LEN: usize = 1 << 30;
+
+/// Write `request` with its ID as a frame.
+pub fn write_request(
+    writer: &mut impl Write,
+    id: u64,
+    request: &Request,
+) -> Result<()> {
+    let mut body = Encoder::default();
+    let (opcode, namespace) = match request {
+        Request::Hello { version, features } => {
+            body.u32(*version).strs(features);
+            return write_frame(writer, HELLO, id, &body.0);
+        }
+        Request::Auth { user, password } => {
+            body.str(user).str(password);
+            return write_frame(writer, AUTH, id, &body.0);
+        }
+        Request::Set {
+            key,
+            value,
+            namespace,
+        } => {
+            body.str(key).str(value);
+            (SET, namespace)
+        }
+        Request::Get { key, namespace } => {
+            body.str(key);
+            (GET, namespace)
+        }
+        Request::Remove { key, namespace } => {
+            body.str(key);
+            (REMOVE, namespace)
+        }
+        Request::MGet { keys, namespace } => {
+            body.strs(keys);
+            (MGET, namespace)
+        }
+        Request::MSet { pairs, namespace } => {
+            body.u32(pairs.len() as u32);
+            for (key, value) in pairs {
+                body.str(key).str(value);
+            }
+            (MSET, namespace)
+        }
+        Request::Incr {
+            key,
+            delta,
+            namespace,
+        } => {
+            body.str(key).u64(*delta as u64);
+            (INCR, namespace)
+        }
+        Request::Transaction { ops, namespace } => {
+            body.u32(ops.len() as u32);
+            for op in ops {
+                match op {
+                    TxOp::Get { key } => body.u8(1).str(key),
+                    TxOp::Set { key, value } => body.u8(2).str(key).str(value),
+                    TxOp::Remove { key } => body.u8(3).str(key),
+                    TxOp::Check { key, value } => {
+                        body.u8(4).str(key).opt(value.as_deref())
+                    }
+                };
+            }
+            (TRANSACTION, namespace)
+        }
+        Request::Watch { prefix, namespace } => {
+            body.str(prefix);
+            (WATCH, namespace)
+        }
+    };
+    body.opt(namespace.as_deref());
+    write_frame(writer, opcode, id, &body.0)
+}
+
+/// Read a request frame, or `None` if the connection is closed before it.
+pub fn read_request(reader: &mut impl Read) -> Result<Option<Tagged<Request>>> {
+    let Some((opcode, id, body)) = read_frame(reader)? else {
+        return Ok(None);
+    };
+    let mut body = Decoder(&body);
+    let request = match opcode {
+        HELLO => Request::Hello {
+            version: body.u32()?,
+            features: body.strings()?,
+        },
+        AUTH => Request::Auth {
+            user: body.string()?,
+            password: body.string()?,
+        },
+        SET => Request::Set {
+            key: body.string()?,
+            value: body.string()?,
+            namespace: body.opt()?,
+        },
+        GET => Request::Get {
+            key: body.string()?,
+            namespace: body.opt()?,
+        },
+        REMOVE => Request::Remove {
+            key: body.string()?,
+            namespace: body.opt()?,
+        },
+        MGET => Request::MGet {
+            keys: body.strings()?,
+            namespace: body.opt()?,
+        },
+        MSET => Request::MSet {
+            pairs: (0..body.u32()?)
+                .map(|_| Ok((body.string()?, body.string()?)))
+                .collect::<Result<_>>()?,
+            namespace: body.opt()?,
+        },
+        INCR => Request::Incr {
+            key: body.string()?,
+            delta: body.u64()? as i64,
+            namespace: body.opt()?,
+        },
+        TRANSACTION => Request::Transaction {
+            ops: (0..body.u32()?)
+                .map(|_| {
+                    Ok(match body.u8()? {
+                        1 => TxOp::Get {
+                            key: body.string()?,
+                        },
+                        2 => TxOp::Set {
+                            key: body.string()?,
+                            value: body.string()?,
+                        },
+                        3 => TxOp::Remove {
+                            key: body.string()?,
+                        },
+                        4 => TxOp::Check {
+                            key: body.string()?,
+                            value: body.opt()?,
+                        },
+                        tag => {
+                            return Err(malformed(format!("operation {tag}")))
+                        }
+                    })
+                })
+                .collect::<Result<_>>()?,
+            namespace: body.opt()?,
+        },
+        WATCH => Request::Watch {
+            prefix: body.string()?,
+            namespace: body.opt()?,
+        },
+        _ => return Err(malformed(format!("opcode {opcode:#04x}"))),
+    };
+    body.finish()?;
+    Ok(Some(Tagged { id, body: request }))
+}
+
+/// Write `response` to the request of `id` as a frame.
+pub fn write_response(
+    writer: &mut impl Write,
+    id: u64,
+    response: &Response,
+) -> Result<()> {
+    let mut body = Encoder::default();
+    let opcode = match response {
+        Response::Hello { version, features } => {
+            body.u32(*version).strs(features);
+            HELLO_REPLY
+        }
+        Response::Ok => OK,
+        Response::Value(value) => {
+            body.opt(value.as_deref());
+            VALUE
+        }
+        Response::Values(values) => {
+            body.u32(values.len() as u32);
+            for value in values {
+                body.opt(value.as_deref());
+            }
+            VALUES
+        }
+        Response::Integer(n) => {
+            body.u64(*n as u64);
+            INTEGER
+        }
+        Response::Event(event) => {
+            body.str(&event.key)
+                .opt(event.value.as_deref())
+                .u64(event.seq);
+            EVENT
+        }
+        Response::Error { code, message } => {
+            let code = match code {
+                ErrorCode::NotFound => 1,
+                ErrorCode::Invalid => 2,
+                ErrorCode::Internal => 3,
+                ErrorCode::Unsupported => 4,
+                ErrorCode::Denied => 5,
+            };
+            body.u8(code).str(message);
+            ERROR
+        }
+    };
+    write_frame(writer, opcode, id, &body.0)
+}
+
+/// Read a response frame, or `None` if the connection is closed before it.
+pub fn read_response(
+    reader: &mut impl Read,
+) -> Result<Option<Tagged<Response>>> {
+    let Some((opcode, id, body)) = read_frame(reader)? else {
+        return Ok(None);
+    };
+    let mut body = Decoder(&body);
+    let response = match opcode {
+        HELLO_REPLY => Response::Hello {
+            version: body.u32()?,
+            features: body.strings()?,
+        },
+        OK => Response::Ok,
+        VALUE => Response::Value(body.opt()?),
+        VALUES => Response::Values(
+            (0..body.u32()?)
+                .map(|_| body.opt())
+                .collect::<Result<_>>()?,
+        ),
+        INTEGER => Response::Integer(body.u64()? as i64),
+        EVENT => Response::Event(Event {
+            key: body.string()?,
+            value: body.opt()?,
+            seq: body.u64()?,
+        }),
+        ERROR => Response::Error {
+            code: match body.u8()? {
+                1 => ErrorCode::NotFound,
+                2 => ErrorCode::Invalid,
+                3 => ErrorCode::Internal,
+                4 => ErrorCode::Unsupported,
+                5 => ErrorCode::Denied,
+                code => return Err(malformed(format!("error code {code}"))),
+            },
+            message: body.string()?,
+        },
+        _ => return Err(malformed(format!("opcode {opcode:#04x}"))),
+    };
+    body.finish()?;
+    Ok(Some(Tagged { id, body: response }))
+}
+
+fn write_frame(
+    writer: &mut impl Write,
+    opcode: u8,
+    id: u64,
+    body: &[u8],
+) -> Result<()> {
+    let len = body.len() + HEADER_LEN;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::Message("message is too large".to_owned()));
+    }
+    writer.write_all(&(len as u32).to_le_bytes())?;
+    writer.write_all(&[opcode])?;
+    writer.write_all(&id.to_le_bytes())?;
+    writer.write_all(body)?;
+    Ok(())
+}
+
+// read the opcode, ID and body of a frame, or `None` at the end of `reader`
+fn read_frame(reader: &mut impl Read) -> Result<Option<(u8, u64, Vec<u8>)>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let len = u32::from_le_bytes(len) as u64;
+    if len < HEADER_LEN as u64 {
+        return Err(malformed("end of header".to_owned()));
+    }
+    if len > MAX_FRAME_LEN as u64 {
+        return Err(malformed(format!("frame length {len}")));
+    }
+    // the length is not trusted, so avoid allocating it up front
+    let mut frame = Vec::new();
+    reader.take(len).read_to_end(&mut frame)?;
+    if (frame.len() as u64) < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let body = frame.split_off(HEADER_LEN);
+    let id = u64::from_le_bytes(frame[1..].try_into().unwrap());
+    Ok(Some((frame[0], id, body)))
+}
+
+fn malformed(what: String) -> Error {
+    Error::Message(format!("malformed frame: unexpected {what}"))
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, n: u8) -> &mut Self {
+        self.0.push(n);
+        self
+    }
+
+    fn u32(&mut self, n: u32) -> &mut Self {
+        self.0.extend_from_slice(&n.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, n: u64) -> &mut Self {
+        self.0.extend_from_slice(&n.to_le_bytes());
+        self
+    }
+
+    fn str(&mut self, s: &str) -> &mut Self {
+        self.u32(s.len() as u32);
+        self.0.extend_from_slice(s.as_bytes());
+        self
+    }
+
+    // a count followed by the strings
+    fn strs(&mut self, strs: &[String]) -> &mut Self {
+        self.u32(strs.len() as u32);
+        for s in strs {
+            self.str(s);
+        }
+        self
+    }
+
+    // a tag of 0 for `None` or 1 followed by the string
+    fn opt(&mut self, s: Option<&str>) -> &mut Self {
+        match s {
+            Some(s) => self.u8(1).str(s),
+            None => self.u8(0),
+        }
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        if self.0.len() < n {
+            return Err(malformed("end of body".to_owned()));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        (0..self.u32()?).map(|_| self.string()).collect()
+    }
+
+    fn opt(&mut self) -> Result<Option<String>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.string()?)),
+            tag => Err(malformed(format!("option tag {tag}"))),
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        if !self.0.is_empty() {
+            return Err(malformed("trailing bytes".to_owned()));
+        }
+        Ok(())
+    }
+}
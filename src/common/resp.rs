@@ -0,0 +1,208 @@
+//! RESP2, the protocol of Redis, for tools like `redis-cli`.
+//!
+//! A command is an array of bulk strings `*<n>\r\n$<len>\r\n<arg>\r\n...`, or
+//! a line of words sent inline, and is answered with a [`Value`].
+
+use crate::{Error, Result};
+use std::io::{self, BufRead, Read, Write};
+
+// longest bulk string accepted, as Redis does
+const MAX_BULK_LEN: usize = 512 << 20;
+
+/// Reply to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// Status like `+OK`
+    Simple(String),
+    /// Failure like `-ERR unknown command`
+    Error(String),
+    Integer(i64),
+    /// Bulk string, `None` for nil
+    Bulk(Option<String>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// Failure of the generic kind `ERR`.
+    pub fn error(message: impl std::fmt::Display) -> Value {
+        Value::Error(format!("ERR {message}"))
+    }
+}
+
+/// Read arguments of a command, or `None` if the connection is closed before
+/// it.
+pub fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<String>>> {
+    loop {
+        let Some(line) = read_line(reader)? else {
+            return Ok(None);
+        };
+        let Some(count) = line.strip_prefix('*') else {
+            let args: Vec<_> =
+                line.split_whitespace().map(str::to_owned).collect();
+            // skip empty lines between inline commands
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        };
+
+        let count = parse_len(count, "multibulk length")?;
+        // the count is not trusted, so avoid allocating it up front
+        let mut args = Vec::new();
+        for _ in 0..count {
+            let line = read_line(reader)?.ok_or_else(eof)?;
+            let Some(len) = line.strip_prefix('$') else {
+                return Err(protocol(format!("expected '$', got '{line}'")));
+            };
+            let len = parse_len(len, "bulk length")?;
+            if len > MAX_BULK_LEN {
+                return Err(protocol("invalid bulk length".to_owned()));
+            }
+            let mut arg = Vec::new();
+            reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+            if arg.len() < len + 2 {
+                return Err(eof());
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol("expected CRLF after bulk".to_owned()));
+            }
+            arg.truncate(len);
+            args.push(String::from_utf8(arg)?);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+/// Write `value` as a reply.
+pub fn write_value(writer: &mut impl Write, value: &Value) -> Result<()> {
+    match value {
+        Value::Simple(s) => write!(writer, "+{s}\r\n")?,
+        Value::Error(message) => write!(writer, "-{message}\r\n")?,
+        Value::Integer(n) => write!(writer, ":{n}\r\n")?,
+        Value::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+        Value::Bulk(Some(s)) => write!(writer, "${}\r\n{s}\r\n", s.len())?,
+        Value::Array(values) => {
+            write!(writer, "*{}\r\n", values.len())?;
+            for value in values {
+                write_value(writer, value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `key` matches a glob-style `pattern` of Redis, where `*` and `?`
+/// match any bytes and any byte, `[...]` matches a byte of a class, and `\`
+/// escapes the next byte.
+pub fn matches(pattern: &str, key: &str) -> bool {
+    glob(pattern.as_bytes(), key.as_bytes())
+}
+
+// on a mismatch, only the last `*` seen takes one more byte, since taking
+// more by an earlier one can only be matched by the last one as well, so the
+// time is linear in `s` for each `*` instead of exponential
+fn glob(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // pattern after the last `*`, and the position in `s` it is tried at
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, i));
+                continue;
+            }
+            Some(_) => {
+                if let Some(len) = match_byte(&pattern[p..], s[i]) {
+                    p += len;
+                    i += 1;
+                    continue;
+                }
+            }
+            None => {}
+        }
+        let Some((after, at)) = star else {
+            return false;
+        };
+        p = after;
+        i = at + 1;
+        star = Some((after, i));
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// length of the token at the start of `pattern` if it matches byte `c`,
+// where the token is not `*`
+fn match_byte(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] | [b'*', ..] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let (negated, mut class) = match rest {
+                [b'^', class @ ..] => (true, class),
+                class => (false, class),
+            };
+            let mut matched = false;
+            // an unclosed class ends with the pattern
+            loop {
+                match class {
+                    [] => break,
+                    [b']', rest @ ..] => {
+                        class = rest;
+                        break;
+                    }
+                    [b'\\', x, rest @ ..] => {
+                        matched |= *x == c;
+                        class = rest;
+                    }
+                    [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                        let (lo, hi) = (*lo.min(hi), *lo.max(hi));
+                        matched |= (lo..=hi).contains(&c);
+                        class = rest;
+                    }
+                    [x, rest @ ..] => {
+                        matched |= *x == c;
+                        class = rest;
+                    }
+                }
+            }
+            (matched != negated).then_some(pattern.len() - class.len())
+        }
+        [b'\\', x, ..] => (*x == c).then_some(2),
+        [x, ..] => (*x == c).then_some(1),
+    }
+}
+
+// read a line without its CRLF, or `None` at the end of `reader`
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    // lines only hold lengths or inline commands, so bound them like bulks
+    reader
+        .take(MAX_BULK_LEN as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(eof());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(String::from_utf8(line)?))
+}
+
+fn parse_len(len: &str, what: &str) -> Result<usize> {
+    len.parse()
+        .map_err(|_| protocol(format!("invalid {what} '{len}'")))
+}
+
+fn protocol(message: String) -> Error {
+    Error::Message(format!("Protocol error: {message}"))
+}
+
+fn eof() -> Error {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+}
@@ -0,0 +1,78 @@
+use crate::{KvsEngine, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// A single line of a dump.
+#[derive(Deserialize, Serialize)]
+struct Record {
+    /// Keyspace of the key, or `None` for the default one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyspace: Option<String>,
+    key: String,
+    value: String,
+}
+
+/// Write all live key-value pairs of every keyspace of `store` to `writer`
+/// as JSON Lines, ordered by keyspace and key, and return the number of
+/// written pairs.
+///
+/// The output does not depend on the engine, so it can be loaded into any
+/// other [`KvsEngine`] with [`load`].
+pub fn dump<E: KvsEngine>(store: &E, mut writer: impl Write) -> Result<u64> {
+    let mut count = dump_keys(store, None, &mut writer)?;
+    for name in store.keyspaces()? {
+        count += dump_keys(&store.keyspace(&name)?, Some(name), &mut writer)?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// write all live key-value pairs of `keyspace`, opened as `store`
+fn dump_keys<E: KvsEngine>(
+    store: &E,
+    keyspace: Option<String>,
+    writer: &mut impl Write,
+) -> Result<u64> {
+    let mut count = 0;
+    for pair in store.scan(..)? {
+        let (key, value) = pair?;
+        let record = Record {
+            keyspace: keyspace.clone(),
+            key,
+            value,
+        };
+        serde_json::to_writer(&mut *writer, &record)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Read key-value pairs produced by [`dump`] from `reader` into `store`,
+/// and return the number of loaded pairs.
+///
+/// Existing keys are overwritten, other keys are left untouched.
+pub fn load<E: KvsEngine>(store: &E, reader: impl Read) -> Result<u64> {
+    let mut count = 0;
+    let mut keyspaces = HashMap::new();
+    for record in Deserializer::from_reader(reader).into_iter::<Record>() {
+        let Record {
+            keyspace,
+            key,
+            value,
+        } = record?;
+        match keyspace {
+            Some(name) => {
+                if !keyspaces.contains_key(&name) {
+                    keyspaces.insert(name.clone(), store.keyspace(&name)?);
+                }
+                keyspaces[&name].set(key, value)?;
+            }
+            None => store.set(key, value)?,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
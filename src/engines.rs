@@ -1,11 +1,107 @@
-use crate::Result;
+use crate::{Error, Result};
+use crossbeam::channel::RecvTimeoutError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+    time::Duration,
+};
 
 mod kvs;
 mod sled;
 
-pub use crate::engines::kvs::KvStore;
+pub use crate::engines::kvs::{
+    CacheStats, Codec, Damage, EncryptionKey, EntryInfo, IndexMode, KvStore,
+    KvStoreOptions, SyncMode, VerifyReport,
+};
 pub use crate::engines::sled::SledStore;
 
+/// Iterator over key-value pairs in ascending key order.
+pub type Scan = Box<dyn Iterator<Item = Result<(String, String)>> + Send>;
+
+/// Change of a key reported by [`KvsEngine::watch`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Event {
+    pub key: String,
+    /// New value of the key, or `None` if it is removed
+    pub value: Option<String>,
+    /// Sequence number of the change, increasing within a watch
+    pub seq: u64,
+}
+
+/// Blocking iterator over changes of watched keys, in the order they are
+/// made.
+///
+/// [`Watch::next_timeout`] waits for a change within a time limit instead.
+pub struct Watch {
+    // wait for the next change, for at most the timeout if given
+    next: Box<dyn FnMut(Option<Duration>) -> Polled + Send>,
+    ended: bool,
+}
+
+type Polled = std::result::Result<Result<Event>, RecvTimeoutError>;
+
+impl Watch {
+    pub(crate) fn new(
+        next: impl FnMut(Option<Duration>) -> Polled + Send + 'static,
+    ) -> Watch {
+        Watch {
+            next: Box::new(next),
+            ended: false,
+        }
+    }
+
+    /// Wait for the next change for at most `timeout`.
+    ///
+    /// Gives `None` if no change is made in time, or the watch has ended as
+    /// told by [`Watch::is_ended`].
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Result<Event>> {
+        self.poll(Some(timeout))
+    }
+
+    /// Whether no more changes will come, such as the store is closed.
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> Option<Result<Event>> {
+        if self.ended {
+            return None;
+        }
+        match (self.next)(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.ended = true;
+                None
+            }
+        }
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        self.poll(None)
+    }
+}
+
+/// Reads and writes of a transaction run by [`KvsEngine::transaction`].
+///
+/// Reads see earlier writes of the transaction, which are not visible to
+/// others before it commits.
+pub trait Transaction {
+    /// Get the [`String`] key's corresponding value.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+
+    /// Set a [`String`] key to a [`String`] value.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+
+    /// Remove a given [`String`] key.
+    fn remove(&mut self, key: String) -> Result<()>;
+}
+
 /// Trait that describes a key/value store engine.
 pub trait KvsEngine: Clone + Send + 'static {
     /// Set a [`String`] key to a [`String`] value.
@@ -16,4 +112,113 @@ pub trait KvsEngine: Clone + Send + 'static {
 
     /// Remove a given [`String`] key.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Get values of `keys`, in the same order.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Set keys to values of `pairs` in order.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Remove all keys in `range`, which may have none.
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<()>;
+
+    /// Remove all keys starting with `prefix`.
+    fn remove_prefix(&self, prefix: String) -> Result<()> {
+        let end = prefix_end(&prefix);
+        self.remove_range((Bound::Included(prefix), end))
+    }
+
+    /// Remove all keys.
+    fn clear(&self) -> Result<()> {
+        self.remove_range(..)
+    }
+
+    /// Replace the value of `key` with `operator` applied to its current
+    /// value, atomically with other writes, and return the new value.
+    ///
+    /// `operator` returns `None` to remove the key, and may be called more
+    /// than once. Nothing is written if it fails.
+    fn merge<F>(&self, key: String, operator: F) -> Result<Option<String>>
+    where
+        F: FnMut(Option<&str>) -> Result<Option<String>>;
+
+    /// Add `delta` to the integer value of `key`, taken as 0 if the key does
+    /// not exist, and return the sum.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let mut sum = 0;
+        self.merge(key, |old| {
+            let old = old.map_or(Ok(0), str::parse::<i64>)?;
+            sum = old.checked_add(delta).ok_or_else(|| {
+                Error::Message(format!("{old} + {delta} overflows"))
+            })?;
+            Ok(Some(sum.to_string()))
+        })?;
+        Ok(sum)
+    }
+
+    /// Run `f` as a transaction, whose writes are applied together only if no
+    /// key it read is changed meanwhile, and return its result.
+    ///
+    /// `f` is run again on such a conflict. Nothing is written if it fails.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut dyn Transaction) -> Result<T>;
+
+    /// Iterate over live key-value pairs whose keys fall in `range`,
+    /// ordered by key.
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<Scan>;
+
+    /// Iterate over live key-value pairs whose keys start with `prefix`,
+    /// ordered by key.
+    fn scan_prefix(&self, prefix: String) -> Result<Scan> {
+        let scan =
+            self.scan((Bound::Included(prefix.clone()), Bound::Unbounded))?;
+        Ok(Box::new(scan.take_while(move |res| match res {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+
+    /// Watch changes of keys starting with `prefix` from now on.
+    ///
+    /// The watch ends when the iterator is dropped.
+    fn watch(&self, prefix: String) -> Result<Watch>;
+
+    /// Get a handle of the keyspace `name`, whose keys are isolated from
+    /// other keyspaces and the default one.
+    fn keyspace(&self, name: &str) -> Result<Self>;
+
+    /// Names of the keyspaces of the store other than the default one, in
+    /// ascending order.
+    fn keyspaces(&self) -> Result<Vec<String>>;
+
+    /// Counters of the whole store by name, for monitoring.
+    fn stats(&self) -> Result<BTreeMap<String, u64>> {
+        Ok(BTreeMap::new())
+    }
+
+    /// Flush writes buffered by the store and sync them to disk.
+    fn sync(&self) -> Result<()>;
+}
+
+// the least string after all strings starting with `prefix`, if any
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end = prefix.to_owned();
+    while let Some(c) = end.pop() {
+        // skip surrogates, which are not chars
+        if let Some(next) =
+            (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32)
+        {
+            end.push(next);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
 }
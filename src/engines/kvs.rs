@@ -1,16 +1,40 @@
 use crate::{
-    engines::kvs::store::{DataReader, DataWriter, EntryPos},
-    KvsEngine, Result,
+    engines::kvs::{
+        cache::ValueCache,
+        index::{DiskIndex, Index, IndexIter},
+        store::{DataReader, DataWriter, Watcher},
+        transaction::KvTransaction,
+    },
+    Error, KvsEngine, Result, Scan, Transaction, Watch,
 };
+use crossbeam::channel::RecvTimeoutError;
 use crossbeam_skiplist::SkipMap;
 use std::{
     cell::RefCell,
     collections::BTreeMap,
+    io::Write,
+    ops::{Bound, RangeBounds},
     path::PathBuf,
-    sync::{atomic::AtomicU64, Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
+mod cache;
+mod check;
+mod index;
+mod options;
 mod store;
+mod transaction;
+mod upgrade;
+
+pub use cache::CacheStats;
+pub use check::{Damage, EntryInfo, VerifyReport};
+pub use options::{Codec, EncryptionKey, IndexMode, KvStoreOptions, SyncMode};
+
+// changes kept for a watch before it is ended for lagging behind
+const WATCH_BACKLOG: usize = 1024;
 
 /// Used for store key-value pairs.
 ///
@@ -18,58 +42,185 @@ mod store;
 ///
 /// ```rust
 /// # use kvs::{KvStore, KvsEngine};
-/// # use std::env::current_dir;
-/// let mut map = KvStore::open(current_dir().unwrap().join(".kv_data")).unwrap();
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let mut map = KvStore::open(temp_dir.path()).unwrap();
 /// map.set("114".to_owned(), "514".to_owned());
 ///
 /// assert_eq!(map.get("114".to_owned()).unwrap(), Some("514".to_owned()));
 /// ```
 #[derive(Clone)]
 pub struct KvStore {
-    // in-memory key index, replace Mutex<BTreeMap<T>> with SkipMap<T>
-    index: Arc<SkipMap<String, EntryPos>>,
+    // key index, kept in memory with SkipMap<T> or on disk
+    index: Index,
     writer: Arc<Mutex<DataWriter>>,
     reader: DataReader,
+    cache: Option<Arc<ValueCache>>,
+    // `{keyspace}\0` prepended to keys, see `Entry::key`
+    prefix: Arc<str>,
 }
 
 impl KvStore {
     /// Open a directory where the database is stored
     /// and create a KvStore which store key-value pairs.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open a directory like [`KvStore::open`] with given options.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<KvStore> {
         let dir_path = path.into();
         std::fs::create_dir_all(&dir_path)?;
 
         let id_list = store::sorted_file_id_list(&dir_path)?;
         let current_id = id_list.last().unwrap_or(&0) + 1;
 
-        let index = Arc::new(SkipMap::new());
-        let mut uncompacted_bytes = 0;
-        for file_id in id_list {
-            uncompacted_bytes +=
-                store::generate_index(&dir_path, file_id, &index)?;
-        }
+        let (index, uncompacted_bytes, last_seq) = match options.index_mode {
+            IndexMode::Memory => {
+                let index = SkipMap::new();
+                let mut uncompacted_bytes = 0;
+                let mut last_seq = 0;
+                for file_id in id_list {
+                    let (bytes, seq) = store::generate_index(
+                        &dir_path, file_id, &index, &options,
+                    )?;
+                    uncompacted_bytes += bytes;
+                    last_seq = last_seq.max(seq);
+                }
+                (Index::Memory(Arc::new(index)), uncompacted_bytes, last_seq)
+            }
+            IndexMode::Disk => {
+                let (index, uncompacted_bytes, last_seq) =
+                    DiskIndex::open(&dir_path, &id_list, &options)?;
+                (Index::Disk(Arc::new(index)), uncompacted_bytes, last_seq)
+            }
+        };
 
+        let cache = (options.cache_size > 0)
+            .then(|| Arc::new(ValueCache::new(options.cache_size)));
         let dir_path = Arc::new(dir_path);
         let reader = DataReader {
             dir_path: dir_path.clone(),
             readers: RefCell::new(BTreeMap::new()),
             last_id: Arc::new(AtomicU64::new(0)),
+            options: options.clone(),
         };
         let writer = DataWriter {
             dir_path: dir_path.clone(),
             index: index.clone(),
             reader: reader.clone(),
-            writer: store::new_entry_writer(&dir_path, current_id)?,
+            writer: store::new_entry_writer(
+                &dir_path, current_id, &options, false,
+            )?,
             current_id,
             uncompacted_bytes,
+            last_seq,
+            watchers: Vec::new(),
+            cache: cache.clone(),
+            options,
         };
 
         Ok(KvStore {
             index,
             writer: Arc::new(Mutex::new(writer)),
             reader,
+            cache,
+            prefix: "\0".into(),
         })
     }
+
+    /// Rewrite live entries into a new data file and drop stale ones.
+    ///
+    /// It happens automatically when enough garbage is collected, and can be
+    /// used to recompress existing values after changing the codec.
+    pub fn compact(&self) -> Result<()> {
+        self.writer.lock().unwrap().compact()
+    }
+
+    /// Counters of the value cache, or `None` if it is disabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// Check every data file in a directory without opening the store.
+    ///
+    /// Corrupted, truncated and out-of-order entries are reported, together
+    /// with readable entries after damaged bytes, which keep the store from
+    /// opening until it is repaired.
+    pub fn verify(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<VerifyReport> {
+        check::verify(&path.into(), options)
+    }
+
+    /// Rewrite all readable entries in a directory into a fresh data file
+    /// with given options, and move damaged data files into its
+    /// `quarantine/` subdirectory.
+    ///
+    /// Nothing is changed if [`KvStore::verify`] finds no problem. The store
+    /// must not be opened during the repair.
+    pub fn repair(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<VerifyReport> {
+        check::repair(&path.into(), options)
+    }
+
+    /// Rewrite data files in a directory written by versions without file
+    /// headers in the current format with given options, and return the
+    /// number of keys written.
+    ///
+    /// Nothing is changed if there is no such file. The store must not be
+    /// opened during the upgrade.
+    pub fn upgrade(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<u64> {
+        upgrade::upgrade(&path.into(), options)
+    }
+
+    /// Visit every readable entry in a directory, ordered by file and
+    /// offset, and return damages skipped on the way.
+    ///
+    /// It is meant for debugging, see [`KvStore::verify`] for checking.
+    pub fn inspect(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+        visit: impl FnMut(EntryInfo),
+    ) -> Result<Vec<Damage>> {
+        check::inspect(&path.into(), options, visit)
+    }
+}
+
+impl KvStore {
+    // key stored in the index and data files
+    fn inner_key(&self, key: &str) -> String {
+        let mut inner = String::with_capacity(self.prefix.len() + key.len());
+        inner.push_str(&self.prefix);
+        inner.push_str(key);
+        inner
+    }
+
+    // sequence number and value of the live entry of an inner key
+    fn lookup(&self, key: String) -> Result<Option<(u64, String)>> {
+        let Some(p) = self.index.get(&key)? else {
+            return Ok(None);
+        };
+        if let Some(value) =
+            self.cache.as_ref().and_then(|cache| cache.get(&key, p.seq))
+        {
+            return Ok(Some((p.seq, value)));
+        }
+
+        let (_, value) = self.reader.locate_value(&p)?;
+        if let Some(cache) = &self.cache {
+            cache.insert(key, p.seq, value.clone());
+        }
+        Ok(Some((p.seq, value)))
+    }
 }
 
 impl KvsEngine for KvStore {
@@ -77,27 +228,272 @@ impl KvsEngine for KvStore {
     ///
     /// The previous value will be overwritten when the key already exists.
     fn set(&self, key: String, value: String) -> Result<()> {
+        let key = self.inner_key(&key);
         self.writer.lock().unwrap().set(key, value)
     }
 
     /// Remove a given [`String`] key.
     ///
-    /// Consider an empty string as a nonexistent value.
+    /// Return [`crate::Error::NonexistentKey`] when the key does not exist.
     fn remove(&self, key: String) -> Result<()> {
+        let key = self.inner_key(&key);
         self.writer.lock().unwrap().remove(key)
     }
 
+    /// Remove all keys in `range` with a single range tombstone.
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<()> {
+        // the key right after `key` is `{key}\0`
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.inner_key(key),
+            Bound::Excluded(key) => self.inner_key(&format!("{key}\0")),
+            Bound::Unbounded => self.prefix.to_string(),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => Some(self.inner_key(&format!("{key}\0"))),
+            Bound::Excluded(key) => Some(self.inner_key(key)),
+            Bound::Unbounded => None,
+        };
+        if end.as_ref().is_some_and(|end| *end <= start) {
+            return Ok(());
+        }
+        self.writer.lock().unwrap().remove_range(start, end)
+    }
+
+    /// Remove all keys of the keyspace, and compact data files to reclaim
+    /// space taken by them.
+    fn clear(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.remove_range(self.prefix.to_string(), None)?;
+        writer.compact()
+    }
+
+    /// Apply `operator` to the current value of `key` while holding the
+    /// writer, so it is called exactly once.
+    fn merge<F>(&self, key: String, operator: F) -> Result<Option<String>>
+    where
+        F: FnMut(Option<&str>) -> Result<Option<String>>,
+    {
+        let key = self.inner_key(&key);
+        self.writer.lock().unwrap().merge(key, operator)
+    }
+
     /// Get the [`String`] key's corresponding value.
     fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(p) = self.index.get(&key) {
-            let (_, value) = self.reader.locate_value(p.value())?;
-            if !value.is_empty() {
-                Ok(Some(value))
-            } else {
-                Ok(None)
+        let key = self.inner_key(&key);
+        Ok(self.lookup(key)?.map(|(_, value)| value))
+    }
+
+    /// Get values of `keys`, reading those not cached in the order of their
+    /// positions in data files.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut values = vec![None; keys.len()];
+        let mut misses = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let key = self.inner_key(key);
+            let Some(p) = self.index.get(&key)? else {
+                continue;
+            };
+            match self.cache.as_ref().and_then(|cache| cache.get(&key, p.seq)) {
+                Some(value) => values[i] = Some(value),
+                None => misses.push((i, key, p)),
             }
-        } else {
-            Ok(None)
         }
+
+        misses.sort_unstable_by_key(|(_, _, p)| (p.file_id(), p.pos()));
+        for (i, key, p) in misses {
+            let (_, value) = self.reader.locate_value(&p)?;
+            if let Some(cache) = &self.cache {
+                cache.insert(key, p.seq, value.clone());
+            }
+            values[i] = Some(value);
+        }
+        Ok(values)
+    }
+
+    /// Set keys to values of `pairs` while holding the writer once.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for (key, value) in pairs {
+            writer.set(self.inner_key(&key), value)?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` as an optimistic transaction.
+    ///
+    /// Sequence numbers of entries read by `f` are checked against the index
+    /// while holding the writer, and `f` is run again if any has changed.
+    /// Reading a key changed since `f` read it fails, and retries `f`
+    /// regardless of its result.
+    fn transaction<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut dyn Transaction) -> Result<T>,
+    {
+        loop {
+            let mut tx = KvTransaction::new(self);
+            let result = f(&mut tx);
+            if tx.conflict {
+                continue;
+            }
+            let result = result?;
+            let (reads, writes) = tx.into_parts();
+            if self.writer.lock().unwrap().commit(reads, writes)? {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// Iterate over live key-value pairs whose keys fall in `range`.
+    ///
+    /// Values are read lazily, so the scan does not hold the whole range in
+    /// memory.
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<Scan> {
+        let lower = match range.start_bound() {
+            Bound::Included(key) => Bound::Included(self.inner_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.inner_key(key)),
+            Bound::Unbounded => Bound::Included(self.prefix.to_string()),
+        };
+        let upper = match range.end_bound() {
+            Bound::Included(key) => Bound::Included(self.inner_key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.inner_key(key)),
+            // keys of the keyspace are before `{keyspace}\u{1}`
+            Bound::Unbounded => Bound::Excluded(format!(
+                "{}\u{1}",
+                &self.prefix[..self.prefix.len() - 1]
+            )),
+        };
+        Ok(Box::new(KvScan {
+            keys: self.index.range(lower, upper),
+            reader: self.reader.clone(),
+            prefix_len: self.prefix.len(),
+        }))
+    }
+
+    /// Watch changes of keys starting with `prefix` from now on.
+    ///
+    /// Events carry sequence numbers of the written entries. A watch which
+    /// falls 1024 changes behind ends after those changes.
+    fn watch(&self, prefix: String) -> Result<Watch> {
+        let (sender, receiver) = crossbeam::channel::bounded(WATCH_BACKLOG);
+        let dropped = Arc::new(AtomicBool::new(false));
+        let mut writer = self.writer.lock().unwrap();
+        // watchers dropped since the last change are forgotten here too
+        writer
+            .watchers
+            .retain(|watcher| !watcher.dropped.load(Ordering::Relaxed));
+        writer.watchers.push(Watcher {
+            prefix: self.inner_key(&prefix),
+            sender,
+            dropped: dropped.clone(),
+        });
+        drop(writer);
+        let guard = WatchGuard(dropped);
+        let prefix_len = self.prefix.len();
+        Ok(Watch::new(move |timeout| {
+            let _guard = &guard;
+            let mut event = match timeout {
+                Some(timeout) => receiver.recv_timeout(timeout)?,
+                None => receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)?,
+            };
+            event.key.drain(..prefix_len);
+            Ok(Ok(event))
+        }))
+    }
+
+    /// Get a handle of the keyspace `name`, which shares data files and
+    /// compaction with the store.
+    ///
+    /// A name is non-empty and does not contain `\0`.
+    fn keyspace(&self, name: &str) -> Result<KvStore> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(Error::Message(format!(
+                "invalid keyspace name {name:?}"
+            )));
+        }
+        Ok(KvStore {
+            prefix: format!("{name}\0").into(),
+            ..self.clone()
+        })
+    }
+
+    /// Names of keyspaces holding keys, which exist as long as they do.
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        // keys of the default keyspace are before `\u{1}`, and those of
+        // keyspace `name` are before `{name}\u{1}`
+        let mut lower = "\u{1}".to_owned();
+        while let Some(first) = self
+            .index
+            .range(Bound::Included(lower), Bound::Unbounded)
+            .next()
+        {
+            let (key, _) = first?;
+            let name = key.split_once('\0').map_or(key.as_str(), |(n, _)| n);
+            lower = format!("{name}\u{1}");
+            names.push(name.to_owned());
+        }
+        Ok(names)
+    }
+
+    /// Sequence number of the last write and bytes of stale entries, with
+    /// counters of the value cache if it is enabled.
+    fn stats(&self) -> Result<BTreeMap<String, u64>> {
+        let mut stats = BTreeMap::new();
+        {
+            let writer = self.writer.lock().unwrap();
+            stats.insert("last_seq".to_owned(), writer.last_seq);
+            stats.insert(
+                "uncompacted_bytes".to_owned(),
+                writer.uncompacted_bytes,
+            );
+        }
+        if let Some(cache) = self.cache_stats() {
+            stats.insert("cache_hits".to_owned(), cache.hits);
+            stats.insert("cache_misses".to_owned(), cache.misses);
+            stats.insert("cache_entries".to_owned(), cache.entries as u64);
+            stats.insert("cache_bytes".to_owned(), cache.bytes as u64);
+        }
+        Ok(stats)
+    }
+
+    /// Flush the data file being written and sync it, from which the index
+    /// is rebuilt on opening.
+    fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.writer.flush()?;
+        writer.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+// read values of keys from the index lazily
+struct KvScan {
+    keys: IndexIter,
+    reader: DataReader,
+    // length of the keyspace prefix to strip from keys
+    prefix_len: usize,
+}
+
+impl Iterator for KvScan {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.keys.next()?.and_then(|(mut key, p)| {
+            let (_, value) = self.reader.locate_value(&p)?;
+            key.drain(..self.prefix_len);
+            Ok((key, value))
+        }))
+    }
+}
+
+// tells writers that a watch is dropped, so that its watcher is forgotten
+// even if no more keys under its prefix change
+struct WatchGuard(Arc<AtomicBool>);
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
     }
 }
@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+// rough cost of a cached value besides its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Counters of the value cache of a [`KvStore`](crate::KvStore).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of cached values
+    pub entries: usize,
+    /// Bytes taken by cached values, at most the configured capacity
+    pub bytes: usize,
+}
+
+struct Slot {
+    key: String,
+    // sequence number of the entry holding the value
+    seq: u64,
+    value: String,
+    // cleared when the clock hand passes, and set again on a hit
+    referenced: bool,
+}
+
+impl Slot {
+    fn cost(&self) -> usize {
+        self.key.len() + self.value.len() + ENTRY_OVERHEAD
+    }
+}
+
+#[derive(Default)]
+struct Clock {
+    map: HashMap<String, usize>,
+    slots: Vec<Option<Slot>>,
+    free: Vec<usize>,
+    hand: usize,
+    bytes: usize,
+}
+
+impl Clock {
+    fn remove(&mut self, key: &str) {
+        if let Some(i) = self.map.remove(key) {
+            let slot = self.slots[i].take().unwrap();
+            self.bytes -= slot.cost();
+            self.free.push(i);
+        }
+    }
+
+    // evict values not referenced since the hand passed them last time
+    fn evict_until(&mut self, capacity: usize) {
+        while self.bytes > capacity {
+            let i = self.hand;
+            self.hand = (self.hand + 1) % self.slots.len();
+            match &mut self.slots[i] {
+                Some(slot) if slot.referenced => slot.referenced = false,
+                Some(slot) => {
+                    let key = slot.key.clone();
+                    self.remove(&key);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+/// Bounded CLOCK cache of decoded values, shared by clones of a store.
+///
+/// Values are tagged with sequence numbers of their entries, so a value read
+/// before a concurrent write is never returned after it.
+pub struct ValueCache {
+    capacity: usize,
+    clock: Mutex<Clock>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            capacity,
+            clock: Mutex::new(Clock::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the value of `key` written by the entry numbered `seq`.
+    pub fn get(&self, key: &str, seq: u64) -> Option<String> {
+        let mut clock = self.clock.lock().unwrap();
+        let value = match clock.map.get(key) {
+            Some(&i) => {
+                let slot = clock.slots[i].as_mut().unwrap();
+                (slot.seq == seq).then(|| {
+                    slot.referenced = true;
+                    slot.value.clone()
+                })
+            }
+            None => None,
+        };
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub fn insert(&self, key: String, seq: u64, value: String) {
+        let slot = Slot {
+            key,
+            seq,
+            value,
+            referenced: false,
+        };
+        if slot.cost() > self.capacity {
+            return;
+        }
+
+        let mut clock = self.clock.lock().unwrap();
+        if let Some(&i) = clock.map.get(&slot.key) {
+            // keep the newer value if readers race
+            if clock.slots[i].as_ref().unwrap().seq >= seq {
+                return;
+            }
+            clock.remove(&slot.key);
+        }
+        clock.evict_until(self.capacity - slot.cost());
+
+        clock.bytes += slot.cost();
+        let i = match clock.free.pop() {
+            Some(i) => i,
+            None => {
+                clock.slots.push(None);
+                clock.slots.len() - 1
+            }
+        };
+        clock.map.insert(slot.key.clone(), i);
+        clock.slots[i] = Some(slot);
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.clock.lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        *self.clock.lock().unwrap() = Clock::default();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let clock = self.clock.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: clock.map.len(),
+            bytes: clock.bytes,
+        }
+    }
+}
@@ -0,0 +1,427 @@
+use crate::{
+    engines::kvs::{
+        index::{self, set_pos, PosMap},
+        options::KvStoreOptions,
+        store::{self, DataFile, DataReader, Entry, EntryPos},
+    },
+    Error, Result,
+};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{self, Seek, SeekFrom},
+    ops::Bound,
+    path::Path,
+    sync::{atomic::AtomicU64, Arc},
+};
+
+/// Problem found in a data file by [`KvStore::verify`](crate::KvStore::verify).
+#[derive(Debug, PartialEq, Eq)]
+pub enum Damage {
+    /// Bytes which cannot be decoded as entries
+    Corrupt { file_id: u64, offset: u64, len: u64 },
+    /// Incomplete entry at the end of a file
+    Truncated { file_id: u64, offset: u64, len: u64 },
+    /// Entry whose sequence number is not greater than previous ones
+    OutOfOrder { file_id: u64, offset: u64, seq: u64 },
+    /// Entries of a transaction which is not completely written
+    Unfinished { file_id: u64, offset: u64, len: u64 },
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Corrupt {
+                file_id,
+                offset,
+                len,
+            } => write!(
+                f,
+                "data-{file_id}: {len} corrupted bytes at offset {offset}"
+            ),
+            Self::Truncated {
+                file_id,
+                offset,
+                len,
+            } => write!(
+                f,
+                "data-{file_id}: truncated entry of {len} bytes at offset \
+                {offset}"
+            ),
+            Self::OutOfOrder {
+                file_id,
+                offset,
+                seq,
+            } => write!(
+                f,
+                "data-{file_id}: entry at offset {offset} has out-of-order \
+                sequence number {seq}"
+            ),
+            Self::Unfinished {
+                file_id,
+                offset,
+                len,
+            } => write!(
+                f,
+                "data-{file_id}: unfinished transaction of {len} bytes at \
+                offset {offset}"
+            ),
+        }
+    }
+}
+
+/// Entry decoded by [`KvStore::inspect`](crate::KvStore::inspect).
+#[derive(Debug)]
+pub struct EntryInfo {
+    pub file_id: u64,
+    pub offset: u64,
+    /// Size of the encoded entry in bytes
+    pub size: u64,
+    pub seq: u64,
+    /// Seconds since the Unix epoch when the entry is written
+    pub timestamp: i64,
+    /// Keyspace of the key, `None` for the default one
+    pub keyspace: Option<String>,
+    pub key: String,
+    pub value_len: u64,
+    pub tombstone: bool,
+    /// End of keys removed by a range tombstone from `key`, where
+    /// [`Bound::Unbounded`] is the end of the keyspace
+    pub range_end: Option<Bound<String>>,
+    /// Whether the index built on open refers to this entry
+    pub live: bool,
+}
+
+/// Summary of data files checked by [`KvStore::verify`](crate::KvStore::verify)
+/// or [`KvStore::repair`](crate::KvStore::repair).
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of data files
+    pub files: usize,
+    /// Number of readable entries
+    pub entries: u64,
+    /// Readable entries after damaged bytes, which make opening the store fail
+    pub unreachable: u64,
+    /// Bytes taken by overwritten or removed entries
+    pub garbage_bytes: u64,
+    /// Problems found in data files
+    pub damages: Vec<Damage>,
+    /// Damaged data files moved into `quarantine/` by repair
+    pub quarantined: Vec<u64>,
+}
+
+impl VerifyReport {
+    /// Whether every entry can be read when opening the store.
+    pub fn is_healthy(&self) -> bool {
+        self.damages.is_empty() && self.unreachable == 0
+    }
+}
+
+// what is found at some position of a data file
+enum Found {
+    Entry {
+        entry: Entry,
+        pos: u64,
+        sz: u64,
+        // whether no damaged bytes are before this entry in the file
+        reachable: bool,
+        // whether entries of the file are ordered by key
+        in_key_order: bool,
+    },
+    Damage(Damage),
+}
+
+// decode every data file in `dir` in order, skipping damaged bytes
+fn walk(
+    dir: &Path,
+    options: &KvStoreOptions,
+    mut visit: impl FnMut(u64, Found),
+) -> Result<usize> {
+    let id_list = store::sorted_file_id_list(dir)?;
+    for &file_id in &id_list {
+        let len = std::fs::metadata(store::data_file_path(dir, file_id))?.len();
+        let mut file = match DataFile::open(dir, file_id, options) {
+            Ok(file) => file,
+            Err(e) if !store::is_damage(&e) => return Err(e),
+            Err(_) => {
+                visit(
+                    file_id,
+                    Found::Damage(Damage::Corrupt {
+                        file_id,
+                        offset: 0,
+                        len,
+                    }),
+                );
+                continue;
+            }
+        };
+
+        let mut pos = file.reader.stream_position()?;
+        let mut reachable = true;
+        // entries of a transaction, visited once the last one is read
+        let mut batch = Vec::new();
+        while pos < len {
+            match file.read_entry() {
+                Ok(entry) => {
+                    let next_pos = file.reader.stream_position()?;
+                    let sz = next_pos - pos;
+                    let more = entry.batch;
+                    batch.push(Found::Entry {
+                        entry,
+                        pos,
+                        sz,
+                        reachable,
+                        in_key_order: file.in_key_order,
+                    });
+                    if !more {
+                        for found in batch.drain(..) {
+                            visit(file_id, found);
+                        }
+                    }
+                    pos = next_pos;
+                }
+                Err(e) if !store::is_damage(&e) => return Err(e),
+                Err(e) => {
+                    if let Some(damage) = unfinished(file_id, &mut batch, pos) {
+                        visit(file_id, Found::Damage(damage));
+                    }
+                    let next_pos = file.resync(pos + 1, len)?;
+                    let damage = match e {
+                        Error::Io(e)
+                            if e.kind() == io::ErrorKind::UnexpectedEof
+                                && next_pos == len =>
+                        {
+                            Damage::Truncated {
+                                file_id,
+                                offset: pos,
+                                len: len - pos,
+                            }
+                        }
+                        _ => Damage::Corrupt {
+                            file_id,
+                            offset: pos,
+                            len: next_pos - pos,
+                        },
+                    };
+                    visit(file_id, Found::Damage(damage));
+                    reachable = false;
+                    pos = next_pos;
+                    file.reader.seek(SeekFrom::Start(pos))?;
+                }
+            }
+        }
+        if let Some(damage) = unfinished(file_id, &mut batch, len) {
+            visit(file_id, Found::Damage(damage));
+        }
+    }
+
+    Ok(id_list.len())
+}
+
+// drop entries of a transaction cut at `end`, and return the damage if any
+fn unfinished(
+    file_id: u64,
+    batch: &mut Vec<Found>,
+    end: u64,
+) -> Option<Damage> {
+    let Some(Found::Entry { pos, .. }) = batch.first() else {
+        return None;
+    };
+    let offset = *pos;
+    batch.clear();
+    Some(Damage::Unfinished {
+        file_id,
+        offset,
+        len: end - offset,
+    })
+}
+
+/// Check every data file in `dir`.
+pub fn verify(dir: &Path, options: &KvStoreOptions) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let mut last_seq = 0;
+    // greatest sequence number in previous files
+    let mut file_seq = (0, 0);
+    // size of the live entry of every key, as the index built on open
+    let mut sizes = BTreeMap::<String, u64>::new();
+    report.files = walk(dir, options, |file_id, found| match found {
+        Found::Entry {
+            entry,
+            pos,
+            sz,
+            reachable,
+            in_key_order,
+        } => {
+            report.entries += 1;
+            if file_seq.0 != file_id {
+                file_seq = (file_id, last_seq);
+            }
+            // entries compacted in key order only follow previous files
+            let min_seq = if in_key_order { file_seq.1 } else { last_seq };
+            if entry.seq <= min_seq {
+                report.damages.push(Damage::OutOfOrder {
+                    file_id,
+                    offset: pos,
+                    seq: entry.seq,
+                });
+            }
+            last_seq = last_seq.max(entry.seq);
+
+            if !reachable {
+                report.unreachable += 1;
+            } else if entry.range {
+                let end = entry.range_end();
+                let removed: Vec<String> = sizes
+                    .range::<str, _>((
+                        Bound::Included(entry.key.as_str()),
+                        Bound::Excluded(end.as_str()),
+                    ))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                for key in removed {
+                    report.garbage_bytes += sizes.remove(&key).unwrap();
+                }
+                report.garbage_bytes += sz;
+            } else {
+                report.garbage_bytes += sizes.remove(&entry.key).unwrap_or(0);
+                if entry.tombstone {
+                    report.garbage_bytes += sz;
+                } else {
+                    sizes.insert(entry.key, sz);
+                }
+            }
+        }
+        Found::Damage(damage) => report.damages.push(damage),
+    })?;
+
+    Ok(report)
+}
+
+/// Rewrite all readable entries in `dir` into a fresh data file with
+/// `options`, and move damaged data files into `quarantine/`.
+pub fn repair(dir: &Path, options: &KvStoreOptions) -> Result<VerifyReport> {
+    let mut report = verify(dir, options)?;
+    if report.is_healthy() {
+        return Ok(report);
+    }
+
+    // replay every readable entry, including those after damaged bytes
+    let index = PosMap::new();
+    let mut damaged = BTreeSet::new();
+    let mut last_id = 0;
+    walk(dir, options, |file_id, found| {
+        last_id = file_id;
+        match found {
+            Found::Entry { entry, .. } if entry.range => {
+                store::remove_index_range(&index, &entry);
+            }
+            Found::Entry { entry, pos, sz, .. } if !entry.tombstone => {
+                match EntryPos::new(file_id, pos, sz, entry.seq) {
+                    Ok(p) => set_pos(&index, entry.key.into(), p),
+                    // the store can not index it either
+                    Err(_) => {
+                        damaged.insert(file_id);
+                    }
+                }
+            }
+            Found::Entry { entry, .. } => {
+                index.remove(entry.key.as_str());
+            }
+            Found::Damage(_) => {
+                damaged.insert(file_id);
+            }
+        }
+    })?;
+
+    let reader = DataReader {
+        dir_path: Arc::new(dir.to_path_buf()),
+        readers: RefCell::new(BTreeMap::new()),
+        last_id: Arc::new(AtomicU64::new(0)),
+        options: options.clone(),
+    };
+    let positions = index
+        .iter()
+        .map(|p| (p.key().clone(), p.value().load()))
+        .collect();
+    store::rewrite_entries(
+        dir,
+        last_id + 1,
+        &reader,
+        &index,
+        positions,
+        options,
+    )?;
+    drop(reader);
+
+    let quarantine = dir.join("quarantine");
+    for file_id in store::sorted_file_id_list(dir)?
+        .into_iter()
+        .filter(|x| *x <= last_id)
+    {
+        let path = store::data_file_path(dir, file_id);
+        if damaged.contains(&file_id) {
+            std::fs::create_dir_all(&quarantine)?;
+            std::fs::rename(path, store::data_file_path(&quarantine, file_id))?;
+        } else {
+            std::fs::remove_file(path)?;
+        }
+        index::remove_index_file(dir, file_id)?;
+    }
+    report.quarantined = damaged.into_iter().collect();
+
+    Ok(report)
+}
+
+/// Visit every readable entry in `dir` in order, and return damages skipped
+/// on the way.
+pub fn inspect(
+    dir: &Path,
+    options: &KvStoreOptions,
+    mut visit: impl FnMut(EntryInfo),
+) -> Result<Vec<Damage>> {
+    let index = PosMap::new();
+    for file_id in store::sorted_file_id_list(dir)? {
+        // entries before damaged bytes are indexed anyway
+        match store::generate_index(dir, file_id, &index, options) {
+            Err(e) if !store::is_damage(&e) => return Err(e),
+            _ => {}
+        }
+    }
+
+    let mut damages = Vec::new();
+    walk(dir, options, |file_id, found| match found {
+        Found::Entry { entry, pos, sz, .. } => {
+            let live = !entry.tombstone
+                && index.get(entry.key.as_str()).is_some_and(|p| {
+                    let p = p.value().load();
+                    p.file_id() == file_id && p.pos() == pos
+                });
+            let (keyspace, key) = store::split_key(&entry.key);
+            let range_end = entry.range.then(|| match entry.value.as_str() {
+                "" => Bound::Unbounded,
+                end => Bound::Excluded(store::split_key(end).1.to_owned()),
+            });
+            visit(EntryInfo {
+                file_id,
+                offset: pos,
+                size: sz,
+                seq: entry.seq,
+                timestamp: entry.timestamp,
+                value_len: if entry.range {
+                    0
+                } else {
+                    entry.value.len() as u64
+                },
+                keyspace: keyspace.map(str::to_owned),
+                key: key.to_owned(),
+                tombstone: entry.tombstone,
+                range_end,
+                live,
+            });
+        }
+        Found::Damage(damage) => damages.push(damage),
+    })?;
+
+    Ok(damages)
+}
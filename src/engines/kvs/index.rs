@@ -0,0 +1,679 @@
+use crate::{
+    engines::kvs::{
+        options::KvStoreOptions,
+        store::{self, DataFile, DataReader, EntryPos, FILE_HEADER_SIZE},
+    },
+    Result,
+};
+use compact_str::CompactString;
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+
+// | magic | version | reserved | data_sz |, where data_sz is the size of the
+// data file covered by the index file
+const INDEX_MAGIC: [u8; 4] = *b"KVSI";
+const INDEX_VERSION: u16 = 2;
+const INDEX_HEADER_SIZE: u64 = 16;
+// | key_sz | flags | pos | sz | seq |, followed by key, ordered by key
+const SLOT_HEADER_SIZE: usize = 29;
+const FLAG_TOMBSTONE: u8 = 1;
+// the first key of every block is kept in memory
+const BLOCK_SIZE: u64 = 4096;
+
+// get path to file `index-{file_id}`
+fn index_file_path(dir_path: &Path, file_id: u64) -> PathBuf {
+    dir_path.join(format!("index-{file_id}"))
+}
+
+/// Remove the index file of `data-{file_id}` if there is one.
+pub fn remove_index_file(dir_path: &Path, file_id: u64) -> Result<()> {
+    match std::fs::remove_file(index_file_path(dir_path, file_id)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Live keys in a range and positions of their entries, ordered by key.
+pub type IndexIter =
+    Box<dyn Iterator<Item = Result<(String, EntryPos)>> + Send>;
+
+/// Map of keys kept in memory, whose values are updated in place, since
+/// replacing an entry of a `SkipMap` hides its key from readers for a moment.
+pub type PosMap<T> = SkipMap<CompactString, AtomicCell<T>>;
+
+/// Point `key` to `value` in `map`, which is only written by one thread.
+pub fn set_pos<T: Copy>(map: &PosMap<T>, key: CompactString, value: T) {
+    map.get_or_insert(key, AtomicCell::new(value))
+        .value()
+        .store(value);
+}
+
+/// Key index of a `KvStore`, which only refers to live entries.
+#[derive(Clone)]
+pub enum Index {
+    Memory(Arc<PosMap<EntryPos>>),
+    Disk(Arc<DiskIndex>),
+}
+
+impl Index {
+    pub fn get(&self, key: &str) -> Result<Option<EntryPos>> {
+        match self {
+            Index::Memory(map) => Ok(map.get(key).map(|p| p.value().load())),
+            Index::Disk(index) => index.get(key),
+        }
+    }
+
+    pub fn insert(&self, key: String, pos: EntryPos) {
+        match self {
+            Index::Memory(map) => set_pos(map, key.into(), pos),
+            Index::Disk(index) => index.insert(key, pos, false),
+        }
+    }
+
+    /// Remove `key`, which is removed by the tombstone at `pos`.
+    pub fn remove(&self, key: String, pos: EntryPos) {
+        match self {
+            Index::Memory(map) => {
+                map.remove(key.as_str());
+            }
+            Index::Disk(index) => index.insert(key, pos, true),
+        }
+    }
+
+    pub fn range(
+        &self,
+        lower: Bound<String>,
+        upper: Bound<String>,
+    ) -> IndexIter {
+        match self {
+            Index::Memory(map) => Box::new(MemoryIter {
+                map: map.clone(),
+                lower,
+                upper,
+            }),
+            Index::Disk(index) => Box::new(index.range(lower, upper)),
+        }
+    }
+}
+
+// walk the index one key at a time, so concurrent writes never invalidate
+// the iterator
+struct MemoryIter {
+    map: Arc<PosMap<EntryPos>>,
+    lower: Bound<String>,
+    upper: Bound<String>,
+}
+
+impl Iterator for MemoryIter {
+    type Item = Result<(String, EntryPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = (
+            self.lower.as_ref().map(String::as_str),
+            self.upper.as_ref().map(String::as_str),
+        );
+        let entry = self.map.range::<str, _>(range).next()?;
+        let key = entry.key().to_string();
+        self.lower = Bound::Excluded(key.clone());
+        Some(Ok((key, entry.value().load())))
+    }
+}
+
+// the latest entry of a key in a data file
+#[derive(Clone, Copy)]
+struct Slot {
+    pos: EntryPos,
+    tombstone: bool,
+}
+
+fn encode_slot(key: &str, slot: &Slot) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(SLOT_HEADER_SIZE + key.len());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.push(if slot.tombstone { FLAG_TOMBSTONE } else { 0 });
+    buf.extend_from_slice(&slot.pos.pos().to_le_bytes());
+    buf.extend_from_slice(&slot.pos.sz().to_le_bytes());
+    buf.extend_from_slice(&slot.pos.seq.to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf
+}
+
+fn read_slot(reader: &mut impl Read, file_id: u64) -> Result<(String, Slot)> {
+    let mut header = [0; SLOT_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let u64_at =
+        |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+
+    let key_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+    let mut key = Vec::new();
+    reader.take(key_len).read_to_end(&mut key)?;
+    if (key.len() as u64) < key_len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let slot = Slot {
+        pos: EntryPos::new(file_id, u64_at(5), u64_at(13), u64_at(21))?,
+        tombstone: header[4] & FLAG_TOMBSTONE != 0,
+    };
+    Ok((String::from_utf8(key)?, slot))
+}
+
+// whether `key` is after the lower bound
+fn above(key: &str, lower: &Bound<String>) -> bool {
+    match lower {
+        Bound::Included(bound) => key >= bound.as_str(),
+        Bound::Excluded(bound) => key > bound.as_str(),
+        Bound::Unbounded => true,
+    }
+}
+
+// whether `key` is before the upper bound
+fn below(key: &str, upper: &Bound<String>) -> bool {
+    match upper {
+        Bound::Included(bound) => key <= bound.as_str(),
+        Bound::Excluded(bound) => key < bound.as_str(),
+        Bound::Unbounded => true,
+    }
+}
+
+// index file `index-{file_id}` of a sealed data file
+struct IndexFile {
+    file_id: u64,
+    file: Mutex<File>,
+    // first key and offset of every block
+    blocks: Vec<(String, u64)>,
+    len: u64,
+}
+
+impl IndexFile {
+    // open `index-{file_id}` and return it with the greatest sequence number,
+    // or `None` if it is missing, damaged or does not cover `data_sz` bytes
+    fn open(
+        dir_path: &Path,
+        file_id: u64,
+        data_sz: u64,
+    ) -> Result<Option<(IndexFile, u64)>> {
+        let file = match File::open(index_file_path(dir_path, file_id)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            file => file?,
+        };
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = [0; INDEX_HEADER_SIZE as usize];
+        if reader.read_exact(&mut header).is_err()
+            || header[..4] != INDEX_MAGIC
+            || header[4..6] != INDEX_VERSION.to_le_bytes()
+            || header[8..] != data_sz.to_le_bytes()
+        {
+            return Ok(None);
+        }
+
+        let mut blocks: Vec<(String, u64)> = Vec::new();
+        let mut last_seq = 0;
+        let mut pos = INDEX_HEADER_SIZE;
+        while pos < len {
+            let Ok((key, slot)) = read_slot(&mut reader, file_id) else {
+                return Ok(None);
+            };
+            let next_pos = pos + (SLOT_HEADER_SIZE + key.len()) as u64;
+            last_seq = last_seq.max(slot.pos.seq);
+            if blocks
+                .last()
+                .is_none_or(|(_, start)| pos >= start + BLOCK_SIZE)
+            {
+                blocks.push((key, pos));
+            }
+            pos = next_pos;
+        }
+
+        let index_file = IndexFile {
+            file_id,
+            file: Mutex::new(reader.into_inner()),
+            blocks,
+            len,
+        };
+        Ok(Some((index_file, last_seq)))
+    }
+
+    // read slots in the i-th block
+    fn read_block(&self, i: usize) -> Result<VecDeque<(String, Slot)>> {
+        let start = self.blocks[i].1;
+        let end = self.blocks.get(i + 1).map_or(self.len, |(_, pos)| *pos);
+        let mut buf = vec![0; (end - start) as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf)?;
+        }
+
+        let mut block = buf.as_slice();
+        let mut slots = VecDeque::new();
+        while !block.is_empty() {
+            slots.push_back(read_slot(&mut block, self.file_id)?);
+        }
+        Ok(slots)
+    }
+
+    // index of the block which may contain `key`
+    fn block_of(&self, key: &str) -> Option<usize> {
+        self.blocks
+            .partition_point(|(first, _)| first.as_str() <= key)
+            .checked_sub(1)
+    }
+
+    // find the slot of `key`, or `None` if it is not in the data file
+    fn get(&self, key: &str) -> Result<Option<Slot>> {
+        let Some(i) = self.block_of(key) else {
+            return Ok(None);
+        };
+        Ok(self
+            .read_block(i)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, slot)| slot))
+    }
+}
+
+// write slots ordered by key into `index-{file_id}`
+struct IndexWriter {
+    path: PathBuf,
+    file_id: u64,
+    writer: BufWriter<File>,
+    blocks: Vec<(String, u64)>,
+    len: u64,
+    last_seq: u64,
+}
+
+impl IndexWriter {
+    fn create(dir_path: &Path, file_id: u64) -> Result<IndexWriter> {
+        let path = index_file_path(dir_path, file_id);
+        // written aside and renamed by `finish`, so a crash never leaves a
+        // partial index file
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.with_extension("tmp"))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&[0; INDEX_HEADER_SIZE as usize])?;
+        Ok(IndexWriter {
+            path,
+            file_id,
+            writer,
+            blocks: Vec::new(),
+            len: INDEX_HEADER_SIZE,
+            last_seq: 0,
+        })
+    }
+
+    fn push(&mut self, key: &str, slot: &Slot) -> Result<()> {
+        if self
+            .blocks
+            .last()
+            .is_none_or(|(_, start)| self.len >= start + BLOCK_SIZE)
+        {
+            self.blocks.push((key.to_owned(), self.len));
+        }
+        let buf = encode_slot(key, slot);
+        self.writer.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        self.last_seq = self.last_seq.max(slot.pos.seq);
+        Ok(())
+    }
+
+    // write the header for a data file of `data_sz` bytes, and return the
+    // index file with the greatest sequence number
+    fn finish(self, data_sz: u64) -> Result<(IndexFile, u64)> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&INDEX_MAGIC)?;
+        file.write_all(&INDEX_VERSION.to_le_bytes())?;
+        file.write_all(&[0; 2])?;
+        file.write_all(&data_sz.to_le_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(self.path.with_extension("tmp"), &self.path)?;
+
+        let index_file = IndexFile {
+            file_id: self.file_id,
+            file: Mutex::new(file),
+            blocks: self.blocks,
+            len: self.len,
+        };
+        Ok((index_file, self.last_seq))
+    }
+}
+
+// build the index file of `data-{file_id}`, which is `data_sz` bytes, where
+// range tombstones remove keys of itself and `older` index files, the newest
+// first
+fn build_index_file(
+    dir_path: &Path,
+    file_id: u64,
+    data_sz: u64,
+    options: &KvStoreOptions,
+    older: &[Arc<IndexFile>],
+) -> Result<(IndexFile, u64)> {
+    let mut file = DataFile::open(dir_path, file_id, options)?;
+    let mut slots = BTreeMap::new();
+    // a torn tail is skipped, as the in-memory index does
+    while let Some(batch) = file.next_batch()? {
+        for (pos, sz, e) in batch {
+            let slot = Slot {
+                pos: EntryPos::new(file_id, pos, sz, e.seq)?,
+                tombstone: e.tombstone,
+            };
+            if !e.range {
+                slots.insert(e.key, slot);
+                continue;
+            }
+
+            // a tombstone for every removed key
+            let (lower, upper) = (
+                Bound::Included(e.key.clone()),
+                Bound::Excluded(e.range_end()),
+            );
+            let mut removed: Vec<_> = slots
+                .range::<String, _>((lower.as_ref(), upper.as_ref()))
+                .map(|(key, _)| key.clone())
+                .collect();
+            let older = MergeIter {
+                memtable: Arc::new(SkipMap::new()),
+                cursors: older
+                    .iter()
+                    .map(|file| Cursor::new(file.clone(), &lower))
+                    .collect(),
+                lower,
+                upper,
+            };
+            for res in older {
+                removed.push(res?.0);
+            }
+            for key in removed {
+                slots.insert(key, slot);
+            }
+        }
+    }
+
+    let mut writer = IndexWriter::create(dir_path, file_id)?;
+    for (key, slot) in &slots {
+        writer.push(key, slot)?;
+    }
+    writer.finish(data_sz)
+}
+
+// remove index files left by data files which no longer exist, and those
+// which are not completely written
+fn remove_stale_index_files(dir_path: &Path, id_list: &[u64]) -> Result<()> {
+    for entry in std::fs::read_dir(dir_path)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(std::ffi::OsStr::to_str)
+        else {
+            continue;
+        };
+        let Some(suffix) = name.strip_prefix("index-") else {
+            continue;
+        };
+        if !suffix
+            .parse::<u64>()
+            .is_ok_and(|file_id| id_list.contains(&file_id))
+        {
+            std::fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Index which only keeps keys of the active data file in memory.
+///
+/// Each sealed data file has an index file `index-{file_id}` holding the
+/// latest entry of every key in it, ordered by key, and only the first key of
+/// every block of it is kept in memory. A lookup reads at most one block of
+/// every index file, from the newest one.
+pub struct DiskIndex {
+    dir_path: PathBuf,
+    // latest entries in the active data file, tombstones included
+    memtable: RwLock<Arc<PosMap<Slot>>>,
+    // index files of sealed data files, the newest first
+    sealed: RwLock<Vec<Arc<IndexFile>>>,
+}
+
+impl DiskIndex {
+    /// Open index files of data files in `id_list`, which are all sealed,
+    /// and build missing ones.
+    ///
+    /// Return bytes taken by stale entries and the greatest sequence number.
+    pub fn open(
+        dir_path: &Path,
+        id_list: &[u64],
+        options: &KvStoreOptions,
+    ) -> Result<(DiskIndex, u64, u64)> {
+        remove_stale_index_files(dir_path, id_list)?;
+
+        let mut sealed = Vec::new();
+        let mut data_bytes = 0;
+        let mut last_seq = 0;
+        for &file_id in id_list {
+            let data_sz =
+                std::fs::metadata(store::data_file_path(dir_path, file_id))?
+                    .len();
+            let (file, seq) = match IndexFile::open(dir_path, file_id, data_sz)?
+            {
+                Some(opened) => opened,
+                None => build_index_file(
+                    dir_path, file_id, data_sz, options, &sealed,
+                )?,
+            };
+            sealed.insert(0, Arc::new(file));
+            data_bytes += data_sz.saturating_sub(FILE_HEADER_SIZE);
+            last_seq = last_seq.max(seq);
+        }
+
+        let index = DiskIndex {
+            dir_path: dir_path.to_path_buf(),
+            memtable: RwLock::new(Arc::new(SkipMap::new())),
+            sealed: RwLock::new(sealed),
+        };
+        let mut live_bytes = 0;
+        for res in index.range(Bound::Unbounded, Bound::Unbounded) {
+            live_bytes += res?.1.sz();
+        }
+        Ok((index, data_bytes.saturating_sub(live_bytes), last_seq))
+    }
+
+    fn get(&self, key: &str) -> Result<Option<EntryPos>> {
+        let live = |slot: &Slot| (!slot.tombstone).then_some(slot.pos);
+        // the memtable is replaced after a sealed index file is added, so
+        // reading them in this order never misses a key
+        let memtable = self.memtable.read().unwrap().clone();
+        if let Some(slot) = memtable.get(key) {
+            return Ok(live(&slot.value().load()));
+        }
+        let sealed = self.sealed.read().unwrap().clone();
+        for file in sealed {
+            if let Some(slot) = file.get(key)? {
+                return Ok(live(&slot));
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert(&self, key: String, pos: EntryPos, tombstone: bool) {
+        let slot = Slot { pos, tombstone };
+        set_pos(&self.memtable.read().unwrap(), key.into(), slot);
+    }
+
+    fn range(&self, lower: Bound<String>, upper: Bound<String>) -> MergeIter {
+        let cursors = self
+            .sealed
+            .read()
+            .unwrap()
+            .iter()
+            .map(|file| Cursor::new(file.clone(), &lower))
+            .collect();
+        MergeIter {
+            memtable: self.memtable.read().unwrap().clone(),
+            cursors,
+            lower,
+            upper,
+        }
+    }
+
+    /// Write the index file of the active data file `data-{file_id}`, which
+    /// is `data_sz` bytes, and start an empty memtable.
+    pub fn seal(&self, file_id: u64, data_sz: u64) -> Result<()> {
+        let memtable = self.memtable.read().unwrap().clone();
+        let mut writer = IndexWriter::create(&self.dir_path, file_id)?;
+        for entry in memtable.iter() {
+            writer.push(entry.key(), &entry.value().load())?;
+        }
+        let (file, _) = writer.finish(data_sz)?;
+
+        self.sealed.write().unwrap().insert(0, Arc::new(file));
+        *self.memtable.write().unwrap() = Arc::new(SkipMap::new());
+        Ok(())
+    }
+
+    /// Rewrite live entries into a new data file `data-{file_id}` ordered by
+    /// key, and replace all index files with the one of it.
+    ///
+    /// The active data file should be sealed before.
+    pub fn compact(
+        &self,
+        file_id: u64,
+        reader: &DataReader,
+        options: &KvStoreOptions,
+    ) -> Result<()> {
+        let mut writer =
+            store::new_entry_writer(&self.dir_path, file_id, options, true)?;
+        let mut index_writer = IndexWriter::create(&self.dir_path, file_id)?;
+        for res in self.range(Bound::Unbounded, Bound::Unbounded) {
+            let (key, p) = res?;
+            let mut e = reader.locate_entry(&p)?;
+            // its transaction is complete, and unfinished ones are not indexed
+            e.batch = false;
+            let pos = store::append_entry(&mut writer, file_id, &e, options)?;
+            index_writer.push(
+                &key,
+                &Slot {
+                    pos,
+                    tombstone: false,
+                },
+            )?;
+        }
+        writer.get_ref().sync_all()?;
+        let (file, _) = index_writer.finish(writer.stream_position()?)?;
+
+        *self.sealed.write().unwrap() = vec![Arc::new(file)];
+        Ok(())
+    }
+}
+
+// slots of an index file read block by block
+struct Cursor {
+    file: Arc<IndexFile>,
+    next_block: usize,
+    slots: VecDeque<(String, Slot)>,
+}
+
+impl Cursor {
+    fn new(file: Arc<IndexFile>, lower: &Bound<String>) -> Cursor {
+        let next_block = match lower {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                file.block_of(key).unwrap_or(0)
+            }
+            Bound::Unbounded => 0,
+        };
+        Cursor {
+            file,
+            next_block,
+            slots: VecDeque::new(),
+        }
+    }
+
+    // drop slots not after `lower`, and read blocks until the first slot is
+    // after it or the file ends
+    fn skip_to(&mut self, lower: &Bound<String>) -> Result<()> {
+        loop {
+            while self
+                .slots
+                .front()
+                .is_some_and(|(key, _)| !above(key, lower))
+            {
+                self.slots.pop_front();
+            }
+            if !self.slots.is_empty()
+                || self.next_block == self.file.blocks.len()
+            {
+                return Ok(());
+            }
+            self.slots = self.file.read_block(self.next_block)?;
+            self.next_block += 1;
+        }
+    }
+}
+
+// merge the memtable and index files one key at a time, where newer entries
+// shadow older ones
+struct MergeIter {
+    memtable: Arc<PosMap<Slot>>,
+    // newest first
+    cursors: Vec<Cursor>,
+    lower: Bound<String>,
+    upper: Bound<String>,
+}
+
+impl MergeIter {
+    fn next_slot(&mut self) -> Result<Option<(String, Slot)>> {
+        for cursor in &mut self.cursors {
+            cursor.skip_to(&self.lower)?;
+        }
+        let range = (
+            self.lower.as_ref().map(String::as_str),
+            self.upper.as_ref().map(String::as_str),
+        );
+        let mut next = self
+            .memtable
+            .range::<str, _>(range)
+            .next()
+            .map(|e| (e.key().to_string(), e.value().load()));
+        for cursor in &self.cursors {
+            if let Some((key, slot)) = cursor.slots.front() {
+                // on a tie the newer one is already taken
+                if below(key, &self.upper)
+                    && next.as_ref().is_none_or(|(k, _)| key < k)
+                {
+                    next = Some((key.clone(), *slot));
+                }
+            }
+        }
+        if let Some((key, _)) = &next {
+            self.lower = Bound::Excluded(key.clone());
+        }
+        Ok(next)
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(String, EntryPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_slot() {
+                Ok(Some((key, slot))) if !slot.tombstone => {
+                    return Some(Ok((key, slot.pos)))
+                }
+                // skip removed keys
+                Ok(Some(_)) => continue,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
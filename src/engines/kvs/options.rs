@@ -0,0 +1,215 @@
+use crate::{Error, Result};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use sha2::{Digest, Sha256};
+use std::{fmt, path::Path};
+
+/// Codec used to compress values in data files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Store values as they are
+    #[default]
+    None,
+    /// LZ4 block format
+    Lz4,
+}
+
+/// Where a [`KvStore`](crate::KvStore) keeps its key index.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexMode {
+    /// Keep every key in memory
+    #[default]
+    Memory,
+    /// Keep keys of sealed data files in sorted index files, with one key
+    /// per block in memory, for datasets larger than memory
+    Disk,
+}
+
+/// When a [`KvStore`](crate::KvStore) syncs written entries to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Hand each write to the OS, which syncs it later
+    #[default]
+    Never,
+    /// Sync each write before it returns
+    Always,
+}
+
+/// Options for opening a [`KvStore`](crate::KvStore).
+///
+/// # Examples
+///
+/// ```rust
+/// # use kvs::{Codec, KvStore, KvStoreOptions};
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let options = KvStoreOptions::new()
+///     .codec(Codec::Lz4)
+///     .compress_threshold(1024);
+/// let store = KvStore::open_with(temp_dir.path(), options).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(crate) codec: Codec,
+    pub(crate) compress_threshold: usize,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) decryption_keys: Vec<EncryptionKey>,
+    pub(crate) index_mode: IndexMode,
+    pub(crate) max_file_size: u64,
+    pub(crate) cache_size: usize,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) sync_mode: SyncMode,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            codec: Codec::None,
+            compress_threshold: 64,
+            encryption_key: None,
+            decryption_keys: Vec::new(),
+            index_mode: IndexMode::Memory,
+            max_file_size: 16 << 20,
+            cache_size: 0,
+            compaction_threshold: 1 << 20,
+            sync_mode: SyncMode::Never,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Create default options, values are not compressed.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Set the codec for newly written values.
+    ///
+    /// Values written with another codec are still readable, and they are
+    /// recompressed with this one on compaction.
+    pub fn codec(mut self, codec: Codec) -> KvStoreOptions {
+        self.codec = codec;
+        self
+    }
+
+    /// Only compress values of at least `bytes` bytes, 64 by default.
+    pub fn compress_threshold(mut self, bytes: usize) -> KvStoreOptions {
+        self.compress_threshold = bytes;
+        self
+    }
+
+    /// Encrypt newly written data files with `key`.
+    ///
+    /// Data files encrypted with other keys are only readable when those keys
+    /// are given by [`KvStoreOptions::decryption_key`], and they are
+    /// encrypted with `key` on compaction.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> KvStoreOptions {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Read data files encrypted with `key`, e.g. during key rotation.
+    pub fn decryption_key(mut self, key: EncryptionKey) -> KvStoreOptions {
+        self.decryption_keys.push(key);
+        self
+    }
+
+    /// Set where the key index is kept, in memory by default.
+    pub fn index_mode(mut self, mode: IndexMode) -> KvStoreOptions {
+        self.index_mode = mode;
+        self
+    }
+
+    /// Seal the active data file once it grows beyond `bytes` bytes, 16 MiB
+    /// by default.
+    ///
+    /// It only applies to [`IndexMode::Disk`], where keys of the active file
+    /// are kept in memory until it is sealed.
+    pub fn max_file_size(mut self, bytes: u64) -> KvStoreOptions {
+        self.max_file_size = bytes;
+        self
+    }
+
+    /// Cache decoded values up to about `bytes` bytes in memory, shared by
+    /// clones of the store. It is disabled by default.
+    pub fn cache_size(mut self, bytes: usize) -> KvStoreOptions {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Compact data files once more than `bytes` bytes of them are stale,
+    /// 1 MiB by default.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Set when writes are synced to disk, [`SyncMode::Never`] by default.
+    pub fn sync_mode(mut self, mode: SyncMode) -> KvStoreOptions {
+        self.sync_mode = mode;
+        self
+    }
+
+    pub(crate) fn key_by_id(&self, id: u64) -> Option<&EncryptionKey> {
+        self.encryption_key
+            .iter()
+            .chain(&self.decryption_keys)
+            .find(|key| key.id() == id)
+    }
+}
+
+/// 256-bit key for authenticated encryption of data files.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Create a key from raw bytes.
+    pub fn new(bytes: [u8; 32]) -> EncryptionKey {
+        EncryptionKey(bytes)
+    }
+
+    /// Parse a key from 64 hexadecimal digits.
+    pub fn from_hex(hex: &str) -> Result<EncryptionKey> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(Error::Message(
+                "an encryption key should be 64 hexadecimal digits".into(),
+            ));
+        }
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(EncryptionKey(bytes))
+    }
+
+    /// Read a key in hexadecimal digits from a file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EncryptionKey> {
+        EncryptionKey::from_hex(&std::fs::read_to_string(path)?)
+    }
+
+    /// Read a key in hexadecimal digits from an environment variable.
+    pub fn from_env(name: &str) -> Result<EncryptionKey> {
+        let hex = std::env::var(name).map_err(|_| {
+            Error::Message(format!("environment variable {name} is not set"))
+        })?;
+        EncryptionKey::from_hex(&hex)
+    }
+
+    /// Identifier of the key written into headers of encrypted data files.
+    ///
+    /// It is derived from the key, and never 0 which stands for plaintext.
+    pub fn id(&self) -> u64 {
+        let digest = Sha256::digest(self.0);
+        u64::from_le_bytes(digest[..8].try_into().unwrap()).max(1)
+    }
+
+    pub(crate) fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the key itself
+        write!(f, "EncryptionKey({:016x})", self.id())
+    }
+}
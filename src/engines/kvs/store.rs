@@ -1,123 +1,564 @@
-use crate::{Error, Result};
+use crate::{
+    engines::kvs::{
+        cache::ValueCache,
+        index::{self, set_pos, Index, PosMap},
+        options::{Codec, EncryptionKey, KvStoreOptions, SyncMode},
+        upgrade,
+    },
+    Error, Event, Result,
+};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, AeadInPlace, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
 use chrono::Utc;
-use crossbeam_skiplist::SkipMap;
+use compact_str::CompactString;
+use crossbeam::channel::Sender;
 use std::{
+    borrow::Cow,
     cell::RefCell,
-    collections::{btree_map, BTreeMap},
+    collections::{btree_map, BTreeMap, HashMap},
     fs::File,
-    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    ops::Bound,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
 };
 
-struct Entry {
-    key: String,
-    // removing a value is considered as setting it to an empty string
-    value: String,
-    timestamp: i64,
+// | magic | version | flags | key id |, where key id is 0 for plaintext
+pub const FILE_MAGIC: [u8; 4] = *b"KVSD";
+const FILE_VERSION: u16 = 2;
+pub const FILE_HEADER_SIZE: u64 = 16;
+// entries are ordered by key instead of sequence number, as written by
+// compaction with an on-disk index
+const FILE_IN_KEY_ORDER: u16 = 1;
+
+// | crc | seq | timestamp | flags | key_sz | value_sz |, followed by key and
+// value, the checksum covers everything after itself
+const ENTRY_HEADER_SIZE: usize = 29;
+// in encrypted files key and value are sealed together, the header after the
+// checksum is authenticated as well: | nonce | ciphertext | tag |
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+const FLAG_TOMBSTONE: u8 = 1;
+// value is compressed with LZ4, prepended by its original size
+const FLAG_LZ4: u8 = 1 << 1;
+// key is in a named keyspace, written as `{keyspace}\0{key}`
+const FLAG_KEYSPACE: u8 = 1 << 2;
+// tombstone of a range of keys, see `Entry::range`
+const FLAG_RANGE: u8 = 1 << 3;
+// more entries of the same transaction follow, see `Entry::batch`
+const FLAG_BATCH: u8 = 1 << 4;
+
+pub struct Entry {
+    pub seq: u64,
+    pub timestamp: i64,
+    // a removed key is recorded by a tombstone without value
+    pub tombstone: bool,
+    // a range tombstone removes keys from `key` to `value`, excluded, where
+    // an empty value is the end of the keyspace of `key`
+    pub range: bool,
+    // more entries of the same transaction follow, and none of them is
+    // applied unless the last one without the flag is written
+    pub batch: bool,
+    // `{keyspace}\0{key}`, where the keyspace is empty by default
+    pub key: String,
+    pub value: String,
 }
 
 impl Entry {
-    pub fn new(key: String, value: String) -> Entry {
+    pub fn new(seq: u64, key: String, value: String) -> Entry {
         Entry {
+            seq,
+            timestamp: Utc::now().timestamp(),
+            tombstone: false,
+            range: false,
+            batch: false,
             key,
             value,
-            timestamp: Utc::now().timestamp(),
         }
     }
+
+    pub fn tombstone(seq: u64, key: String) -> Entry {
+        Entry {
+            tombstone: true,
+            ..Entry::new(seq, key, String::new())
+        }
+    }
+
+    /// Tombstone of keys from `start` to `end`, excluded, or to the end of
+    /// the keyspace of `start` if `end` is `None`.
+    pub fn range_tombstone(
+        seq: u64,
+        start: String,
+        end: Option<String>,
+    ) -> Entry {
+        Entry {
+            tombstone: true,
+            range: true,
+            ..Entry::new(seq, start, end.unwrap_or_default())
+        }
+    }
+
+    /// End of keys removed by a range tombstone, excluded.
+    pub fn range_end(&self) -> String {
+        if self.value.is_empty() {
+            // keys of the keyspace are before `{keyspace}\u{1}`
+            let (keyspace, _) = self.key.split_once('\0').unwrap_or_default();
+            format!("{keyspace}\u{1}")
+        } else {
+            self.value.clone()
+        }
+    }
+
+    fn encode(&self, options: &KvStoreOptions) -> Result<Vec<u8>> {
+        let mut flags = if self.tombstone { FLAG_TOMBSTONE } else { 0 };
+        if self.range {
+            flags |= FLAG_RANGE;
+        }
+        if self.batch {
+            flags |= FLAG_BATCH;
+        }
+        // keys in the default keyspace are written as they are
+        let key = match self.key.strip_prefix('\0') {
+            Some(key) => key,
+            None => {
+                flags |= FLAG_KEYSPACE;
+                &self.key
+            }
+        };
+        let mut value = Cow::Borrowed(self.value.as_bytes());
+        if options.codec == Codec::Lz4
+            && value.len() >= options.compress_threshold
+        {
+            let compressed = lz4_flex::compress_prepend_size(&value);
+            // keep incompressible values as they are
+            if compressed.len() < value.len() {
+                flags |= FLAG_LZ4;
+                value = Cow::Owned(compressed);
+            }
+        }
+
+        let len = ENTRY_HEADER_SIZE + key.len() + value.len();
+        // sizes are stored in 32 bits, here and in the index
+        if len + NONCE_SIZE + TAG_SIZE > u32::MAX as usize {
+            return Err(Error::Message(format!(
+                "entry of key {key:?} is too large"
+            )));
+        }
+        let mut buf = Vec::with_capacity(len);
+        buf.extend_from_slice(&[0; 4]);
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(&self.timestamp.to_le_bytes());
+        buf.push(flags);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if let Some(secret) = &options.encryption_key {
+            let mut payload =
+                Vec::with_capacity(key.len() + value.len() + TAG_SIZE);
+            payload.extend_from_slice(key.as_bytes());
+            payload.extend_from_slice(&value);
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            secret
+                .cipher()
+                .encrypt_in_place(&nonce, &buf[4..], &mut payload)
+                .map_err(|_| Error::Message("unable to encrypt".into()))?;
+            buf.extend_from_slice(&nonce);
+            buf.extend_from_slice(&payload);
+        } else {
+            buf.extend_from_slice(key.as_bytes());
+            buf.extend_from_slice(&value);
+        }
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        Ok(buf)
+    }
 }
 
-#[derive(Debug)]
+/// Position of an entry, packed into 24 bytes since the in-memory index
+/// keeps one for every key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct EntryPos {
-    file_id: u64,
-    pos: u64,
-    sz: u64,
-    timestamp: i64,
+    pub seq: u64,
+    file_id: u32,
+    sz: u32,
+    // 48-bit offset in the file
+    pos_lo: u32,
+    pos_hi: u16,
 }
 
-fn append_entry(
+impl EntryPos {
+    /// Fails if the position does not fit into the packed fields.
+    pub fn new(file_id: u64, pos: u64, sz: u64, seq: u64) -> Result<EntryPos> {
+        if file_id > u32::MAX as u64 || pos >= 1 << 48 || sz > u32::MAX as u64 {
+            return Err(Error::Message(format!(
+                "entry position out of range: file {file_id}, offset {pos}, \
+                 size {sz}"
+            )));
+        }
+        Ok(EntryPos {
+            seq,
+            file_id: file_id as u32,
+            sz: sz as u32,
+            pos_lo: pos as u32,
+            pos_hi: (pos >> 32) as u16,
+        })
+    }
+
+    pub fn file_id(&self) -> u64 {
+        self.file_id as u64
+    }
+
+    pub fn pos(&self) -> u64 {
+        (self.pos_hi as u64) << 32 | self.pos_lo as u64
+    }
+
+    pub fn sz(&self) -> u64 {
+        self.sz as u64
+    }
+}
+
+pub fn append_entry(
     writer: &mut BufWriter<File>,
     file_id: u64,
     e: &Entry,
+    options: &KvStoreOptions,
 ) -> Result<EntryPos> {
+    Ok(append_entries(writer, file_id, std::slice::from_ref(e), options)?[0])
+}
+
+/// Append `entries` with one write, and return their positions.
+pub fn append_entries(
+    writer: &mut BufWriter<File>,
+    file_id: u64,
+    entries: &[Entry],
+    options: &KvStoreOptions,
+) -> Result<Vec<EntryPos>> {
     let pos = writer.stream_position()?;
-    // | timestamp | key_sz | value_sz | key | value |
-    writer.write_all(&e.timestamp.to_ne_bytes())?;
-    writer.write_all(&e.key.len().to_ne_bytes())?;
-    writer.write_all(&e.value.len().to_ne_bytes())?;
-    writer.write_all(e.key.as_bytes())?;
-    writer.write_all(e.value.as_bytes())?;
-    let sz = writer.stream_position()? - pos;
-
-    Ok(EntryPos {
-        file_id,
-        pos,
-        sz,
-        timestamp: e.timestamp,
-    })
+    let mut buf = Vec::new();
+    let mut positions = Vec::with_capacity(entries.len());
+    for e in entries {
+        let start = buf.len() as u64;
+        buf.extend(e.encode(options)?);
+        // check before writing so that no entry is left unindexable
+        let sz = buf.len() as u64 - start;
+        positions.push(EntryPos::new(file_id, pos + start, sz, e.seq)?);
+    }
+    writer.write_all(&buf)?;
+    writer.flush()?;
+
+    Ok(positions)
 }
 
-fn read_entry(reader: &mut BufReader<File>) -> Result<Entry> {
-    let mut i64_buf = [0; (i64::BITS as usize) / 8];
-    reader.read_exact(&mut i64_buf)?;
-    let timestamp = i64::from_ne_bytes(i64_buf);
+// decode the entry at the current position of `reader`, which is encrypted
+// with `key` if given, with `left` bytes left in the file
+fn read_entry(
+    reader: &mut impl Read,
+    key: Option<&EncryptionKey>,
+    left: u64,
+) -> Result<Entry> {
+    let mut header = [0; ENTRY_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let u32_at =
+        |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+    let u64_at =
+        |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+
+    let flags = header[20];
+    let known =
+        FLAG_TOMBSTONE | FLAG_LZ4 | FLAG_KEYSPACE | FLAG_RANGE | FLAG_BATCH;
+    if flags & !known != 0
+        || flags & (FLAG_TOMBSTONE | FLAG_RANGE) == FLAG_RANGE
+    {
+        return Err(Error::Corrupted(format!("unknown flags {flags:#x}")));
+    }
+    let key_len = u32_at(21) as usize;
+    let value_len = u32_at(25) as usize;
+
+    let body_len = match key {
+        Some(_) => NONCE_SIZE + key_len + value_len + TAG_SIZE,
+        None => key_len + value_len,
+    };
+
+    // lengths are not trusted before the checksum is verified, so avoid
+    // allocating them up front, or reading past the file
+    if (ENTRY_HEADER_SIZE + body_len) as u64 > left {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let mut body = Vec::new();
+    reader.take(body_len as u64).read_to_end(&mut body)?;
+    if body.len() < body_len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
 
-    let mut usize_buf = [0; (usize::BITS as usize) / 8];
-    reader.read_exact(&mut usize_buf)?;
-    let key_len = usize::from_ne_bytes(usize_buf);
-    reader.read_exact(&mut usize_buf)?;
-    let value_len = usize::from_ne_bytes(usize_buf);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(&body);
+    if hasher.finalize() != u32_at(0) {
+        return Err(Error::Corrupted("checksum mismatch".into()));
+    }
 
-    let mut key = vec![0; key_len];
-    reader.read_exact(key.as_mut())?;
-    let mut value = vec![0; value_len];
-    reader.read_exact(value.as_mut())?;
+    if let Some(key) = key {
+        let (nonce, ciphertext) = body.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &header[4..],
+        };
+        body = key
+            .cipher()
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| Error::Corrupted("unable to decrypt".into()))?;
+    }
 
+    let mut value = body.split_off(key_len);
+    if flags & FLAG_KEYSPACE == 0 {
+        body.insert(0, b'\0');
+    }
+    if flags & FLAG_LZ4 != 0 {
+        value = lz4_flex::decompress_size_prepended(&value)
+            .map_err(|e| Error::Corrupted(e.to_string()))?;
+    }
     Ok(Entry {
-        key: String::from_utf8(key)?,
+        seq: u64_at(4),
+        timestamp: u64_at(12) as i64,
+        tombstone: flags & FLAG_TOMBSTONE != 0,
+        range: flags & FLAG_RANGE != 0,
+        batch: flags & FLAG_BATCH != 0,
+        key: String::from_utf8(body)?,
         value: String::from_utf8(value)?,
-        timestamp,
     })
 }
 
+/// Split a stored key into its keyspace, `None` for the default one, and
+/// the key itself.
+pub fn split_key(key: &str) -> (Option<&str>, &str) {
+    let (keyspace, key) = key.split_once('\0').unwrap_or(("", key));
+    ((!keyspace.is_empty()).then_some(keyspace), key)
+}
+
 // get path to file `data-{file_id}`
-fn data_file_path(dir_path: &Path, file_id: u64) -> PathBuf {
+pub fn data_file_path(dir_path: &Path, file_id: u64) -> PathBuf {
     dir_path.join(format!("data-{file_id}"))
 }
 
+/// A data file opened for reading.
+pub struct DataFile {
+    pub reader: BufReader<File>,
+    file_id: u64,
+    // whether entries are ordered by key instead of sequence number
+    pub in_key_order: bool,
+    // key of encrypted entries
+    key: Option<EncryptionKey>,
+    // length of the file when last checked, which only grows
+    len: u64,
+}
+
+impl DataFile {
+    /// Open the data file `data-{file_id}` and check its header.
+    ///
+    /// The reader is positioned at the first entry.
+    pub fn open(
+        dir_path: &Path,
+        file_id: u64,
+        options: &KvStoreOptions,
+    ) -> Result<DataFile> {
+        let file = File::open(data_file_path(dir_path, file_id))?;
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        // a crash may happen between creating a file and writing its header
+        if len == 0 {
+            return Ok(DataFile {
+                reader,
+                file_id,
+                in_key_order: false,
+                key: None,
+                len,
+            });
+        }
+
+        let mut header = [0; FILE_HEADER_SIZE as usize];
+        let read = reader.read_exact(&mut header);
+        if read.is_err() || header[..4] != FILE_MAGIC {
+            if upgrade::is_legacy(dir_path, file_id)? {
+                return Err(Error::Message(format!(
+                    "data-{file_id} is written by an older version, rewrite \
+                    it with `kvs upgrade --data-dir {}`",
+                    dir_path.display()
+                )));
+            }
+            read?;
+            return Err(Error::Corrupted(format!(
+                "data-{file_id} has no valid header"
+            )));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != FILE_VERSION {
+            return Err(Error::Corrupted(format!(
+                "data-{file_id} is written in unsupported version {version}"
+            )));
+        }
+        let flags = u16::from_le_bytes([header[6], header[7]]);
+        let key = match u64::from_le_bytes(header[8..].try_into().unwrap()) {
+            0 => None,
+            id => Some(options.key_by_id(id).cloned().ok_or_else(|| {
+                Error::Message(format!(
+                    "data-{file_id} is encrypted with key {id:016x}, which is \
+                    not given"
+                ))
+            })?),
+        };
+        Ok(DataFile {
+            reader,
+            file_id,
+            in_key_order: flags & FILE_IN_KEY_ORDER != 0,
+            key,
+            len,
+        })
+    }
+
+    /// Decode the entry at the current position.
+    ///
+    /// An incomplete entry is reported as [`io::ErrorKind::UnexpectedEof`],
+    /// and a damaged one as [`Error::Corrupted`].
+    pub fn read_entry(&mut self) -> Result<Entry> {
+        let pos = self.reader.stream_position()?;
+        let left = self.len.saturating_sub(pos);
+        let entry = read_entry(&mut self.reader, self.key.as_ref(), left);
+        if let Err(Error::Io(e)) = &entry {
+            // the entry may be appended since the length is checked
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                let len = self.reader.get_ref().metadata()?.len();
+                if len > self.len {
+                    self.len = len;
+                    self.reader.seek(SeekFrom::Start(pos))?;
+                    return self.read_entry();
+                }
+            }
+        }
+        entry
+    }
+
+    /// Read entries of the next write with their positions and sizes, which
+    /// are more than one for a transaction.
+    ///
+    /// Entries of a transaction are only returned if all of them are
+    /// readable.
+    pub fn read_batch(&mut self) -> Result<Vec<(u64, u64, Entry)>> {
+        let mut batch = Vec::new();
+        loop {
+            let pos = self.reader.stream_position()?;
+            let e = self.read_entry()?;
+            let next_pos = self.reader.stream_position()?;
+            let more = e.batch;
+            batch.push((pos, next_pos - pos, e));
+            if !more {
+                return Ok(batch);
+            }
+        }
+    }
+
+    /// Read entries of the next write like [`DataFile::read_batch`], or
+    /// `None` after the last readable one.
+    ///
+    /// Damaged bytes are only skipped at the end of the file, where a crash
+    /// may leave them, and fail with [`Error::Corrupted`] if readable entries
+    /// follow them.
+    pub fn next_batch(&mut self) -> Result<Option<Vec<(u64, u64, Entry)>>> {
+        let pos = self.reader.stream_position()?;
+        match self.read_batch() {
+            Ok(batch) => Ok(Some(batch)),
+            Err(e) if !is_damage(&e) => Err(e),
+            Err(_) => {
+                let len = self.reader.get_ref().metadata()?.len();
+                if self.resync(pos + 1, len)? < len {
+                    return Err(Error::Corrupted(format!(
+                        "data-{} is damaged at offset {pos} before readable \
+                        entries, check it with `kvs verify` and keep them \
+                        with `kvs repair`",
+                        self.file_id
+                    )));
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Find the next position from `from` where an entry can be decoded, or
+    /// `len` if there is none.
+    pub fn resync(&mut self, from: u64, len: u64) -> Result<u64> {
+        for pos in from..len {
+            self.reader.seek(SeekFrom::Start(pos))?;
+            if self.read_entry().is_ok() {
+                return Ok(pos);
+            }
+        }
+        Ok(len)
+    }
+}
+
+/// Whether `e` is caused by damaged bytes rather than the environment.
+pub fn is_damage(e: &Error) -> bool {
+    match e {
+        Error::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        Error::Corrupted(_) | Error::Utf8(_) => true,
+        _ => false,
+    }
+}
+
 /// Generate in-memory index used in `KvStore` for given reader.
+///
+/// Return bytes taken by stale entries and the greatest sequence number.
 pub fn generate_index(
     dir_path: &Path,
     file_id: u64,
-    index: &SkipMap<String, EntryPos>,
-) -> Result<u64> {
-    let mut reader =
-        BufReader::new(File::open(data_file_path(dir_path, file_id))?);
+    index: &PosMap<EntryPos>,
+    options: &KvStoreOptions,
+) -> Result<(u64, u64)> {
+    let mut file = DataFile::open(dir_path, file_id, options)?;
     let mut uncompacted_bytes = 0;
-    let mut pos = 0;
-    reader.seek(SeekFrom::Start(0))?;
-    while let Ok(e) = read_entry(&mut reader) {
-        let next_pos = reader.stream_position()?;
-        if index.contains_key(&e.key) {
-            let old_e = index.get(&e.key).unwrap();
-            uncompacted_bytes += old_e.value().sz;
-        }
-        index.insert(
-            e.key,
-            EntryPos {
-                file_id,
-                pos,
-                sz: next_pos - pos,
-                timestamp: e.timestamp,
-            },
-        );
-        pos = next_pos;
+    let mut last_seq = 0;
+    // a torn tail is skipped, see `kvs verify`
+    while let Some(batch) = file.next_batch()? {
+        for (pos, sz, e) in batch {
+            last_seq = last_seq.max(e.seq);
+            if e.range {
+                uncompacted_bytes += sz + remove_index_range(index, &e);
+                continue;
+            }
+            if let Some(old_e) = index.get(e.key.as_str()) {
+                uncompacted_bytes += old_e.value().load().sz();
+            }
+            if e.tombstone {
+                uncompacted_bytes += sz;
+                index.remove(e.key.as_str());
+            } else {
+                let p = EntryPos::new(file_id, pos, sz, e.seq)?;
+                set_pos(index, e.key.into(), p);
+            }
+        }
     }
 
-    Ok(uncompacted_bytes)
+    Ok((uncompacted_bytes, last_seq))
+}
+
+/// Remove keys removed by the range tombstone `e` from `index`, and return
+/// bytes taken by their entries.
+pub fn remove_index_range(index: &PosMap<EntryPos>, e: &Entry) -> u64 {
+    let end = e.range_end();
+    let range = (
+        Bound::Included(e.key.as_str()),
+        Bound::Excluded(end.as_str()),
+    );
+    index
+        .range::<str, _>(range)
+        .map(|old_e| {
+            old_e.remove();
+            old_e.value().load().sz()
+        })
+        .sum()
 }
 
 pub fn sorted_file_id_list(dir_path: &std::path::Path) -> Result<Vec<u64>> {
@@ -136,20 +577,33 @@ pub fn sorted_file_id_list(dir_path: &std::path::Path) -> Result<Vec<u64>> {
     Ok(id_list)
 }
 
-const COMPACTION_THRESHOLD_BYTES: u64 = 1 << 20;
-
 pub struct DataWriter {
     pub dir_path: Arc<PathBuf>,
-    pub index: Arc<SkipMap<String, EntryPos>>,
+    pub index: Index,
     pub writer: BufWriter<File>,
     pub reader: DataReader,
     pub current_id: u64,
     pub uncompacted_bytes: u64,
+    // sequence number of the last written entry
+    pub last_seq: u64,
+    pub watchers: Vec<Watcher>,
+    pub cache: Option<Arc<ValueCache>>,
+    pub options: KvStoreOptions,
+}
+
+// channel of a watch of keys starting with `prefix`
+pub struct Watcher {
+    pub prefix: String,
+    pub sender: Sender<Event>,
+    // set once the watch is dropped
+    pub dropped: Arc<AtomicBool>,
 }
 
 pub fn new_entry_writer(
     dir_path: &Path,
     file_id: u64,
+    options: &KvStoreOptions,
+    in_key_order: bool,
 ) -> Result<BufWriter<File>> {
     let mut writer = BufWriter::new(
         std::fs::OpenOptions::new()
@@ -157,72 +611,293 @@ pub fn new_entry_writer(
             .append(true)
             .open(data_file_path(dir_path, file_id))?,
     );
-    writer.seek(SeekFrom::End(0))?;
+    if writer.seek(SeekFrom::End(0))? == 0 {
+        writer.write_all(&FILE_MAGIC)?;
+        writer.write_all(&FILE_VERSION.to_le_bytes())?;
+        let flags = if in_key_order { FILE_IN_KEY_ORDER } else { 0 };
+        writer.write_all(&flags.to_le_bytes())?;
+        let key_id = options.encryption_key.as_ref().map_or(0, |key| key.id());
+        writer.write_all(&key_id.to_le_bytes())?;
+        writer.flush()?;
+    }
     Ok(writer)
 }
 
+/// Write entries at `positions` into a new file `data-{file_id}`, ordered by
+/// their sequence numbers, and point `index` to the new positions.
+///
+/// Entries are encoded again with `options`.
+pub fn rewrite_entries(
+    dir_path: &Path,
+    file_id: u64,
+    reader: &DataReader,
+    index: &PosMap<EntryPos>,
+    mut positions: Vec<(CompactString, EntryPos)>,
+    options: &KvStoreOptions,
+) -> Result<()> {
+    let mut writer = new_entry_writer(dir_path, file_id, options, false)?;
+    positions.sort_unstable_by_key(|(_, p)| p.seq);
+    for (key, p) in positions {
+        let mut e = reader.locate_entry(&p)?;
+        // the transaction of the entry is complete, and others are not copied
+        e.batch = false;
+        let p = append_entry(&mut writer, file_id, &e, options)?;
+        set_pos(index, key, p);
+    }
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
 impl DataWriter {
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let e = Entry::new(key, value);
-        let p = append_entry(&mut self.writer, self.current_id, &e)?;
+        let old_p = self.index.get(&key)?;
+        self.last_seq += 1;
+        let e = Entry::new(self.last_seq, key, value);
+        let p =
+            append_entry(&mut self.writer, self.current_id, &e, &self.options)?;
+
+        if let Some(old_p) = old_p {
+            self.uncompacted_bytes += old_p.sz();
+        }
+        let end = p.pos() + p.sz();
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&e.key);
+        }
+        self.index.insert(e.key.clone(), p);
+        self.notify(e.into());
+        self.maintain(end)
+    }
 
-        if let Some(old_p) = self.index.get(&e.key) {
-            self.uncompacted_bytes += old_p.value().sz;
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        if let Some(old_p) = self.index.get(&key)? {
+            self.last_seq += 1;
+            let e = Entry::tombstone(self.last_seq, key);
+            let p = append_entry(
+                &mut self.writer,
+                self.current_id,
+                &e,
+                &self.options,
+            )?;
+
+            self.uncompacted_bytes += p.sz() + old_p.sz();
+            let end = p.pos() + p.sz();
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&e.key);
+            }
+            self.index.remove(e.key.clone(), p);
+            self.notify(e.into());
+            self.maintain(end)
+        } else {
+            Err(Error::NonexistentKey)
         }
-        self.index.insert(e.key, p);
-        if self.uncompacted_bytes > COMPACTION_THRESHOLD_BYTES {
-            self.compact()?;
+    }
+
+    pub fn merge<F>(
+        &mut self,
+        key: String,
+        mut operator: F,
+    ) -> Result<Option<String>>
+    where
+        F: FnMut(Option<&str>) -> Result<Option<String>>,
+    {
+        let old = match self.index.get(&key)? {
+            Some(p) => Some(self.reader.locate_value(&p)?.1),
+            None => None,
+        };
+        let new = operator(old.as_deref())?;
+        match &new {
+            Some(value) => self.set(key, value.clone())?,
+            None if old.is_some() => self.remove(key)?,
+            None => {}
         }
+        Ok(new)
+    }
 
-        Ok(())
+    /// Apply `writes` of a transaction, `None` to remove a key, if keys in
+    /// `reads` still have the entries numbered by them, `None` for absent
+    /// keys. Return whether it is committed.
+    pub fn commit(
+        &mut self,
+        reads: HashMap<String, Option<u64>>,
+        writes: BTreeMap<String, Option<String>>,
+    ) -> Result<bool> {
+        for (key, seq) in reads {
+            if self.index.get(&key)?.map(|p| p.seq) != seq {
+                return Ok(false);
+            }
+        }
+        let mut entries = Vec::new();
+        for (key, value) in writes {
+            let seq = self.last_seq + entries.len() as u64 + 1;
+            match value {
+                Some(value) => entries.push(Entry::new(seq, key, value)),
+                // the key may be set and removed by the transaction only
+                None if self.index.get(&key)?.is_some() => {
+                    entries.push(Entry::tombstone(seq, key))
+                }
+                None => {}
+            }
+        }
+        let Some(last) = entries.len().checked_sub(1) else {
+            return Ok(true);
+        };
+        // written at once, and skipped on open unless the last one is there
+        for e in &mut entries[..last] {
+            e.batch = true;
+        }
+        let positions = append_entries(
+            &mut self.writer,
+            self.current_id,
+            &entries,
+            &self.options,
+        )?;
+
+        self.last_seq += entries.len() as u64;
+        let mut end = 0;
+        for (e, p) in entries.into_iter().zip(positions) {
+            if let Some(old_p) = self.index.get(&e.key)? {
+                self.uncompacted_bytes += old_p.sz();
+            }
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&e.key);
+            }
+            if e.tombstone {
+                self.uncompacted_bytes += p.sz();
+                self.index.remove(e.key.clone(), p);
+            } else {
+                self.index.insert(e.key.clone(), p);
+            }
+            end = p.pos() + p.sz();
+            self.notify(e.into());
+        }
+        self.maintain(end)?;
+        Ok(true)
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let e = Entry::new(key, "".into());
-            let p = append_entry(&mut self.writer, self.current_id, &e)?;
+    /// Remove live keys from `start` to `end`, excluded, or to the end of the
+    /// keyspace of `start` if `end` is `None`, with one range tombstone.
+    pub fn remove_range(
+        &mut self,
+        start: String,
+        end: Option<String>,
+    ) -> Result<()> {
+        let e = Entry::range_tombstone(self.last_seq + 1, start, end);
+        let mut keys = self
+            .index
+            .range(
+                Bound::Included(e.key.clone()),
+                Bound::Excluded(e.range_end()),
+            )
+            .peekable();
+        if keys.peek().is_none() {
+            return Ok(());
+        }
+        self.last_seq += 1;
+        let p =
+            append_entry(&mut self.writer, self.current_id, &e, &self.options)?;
+
+        self.uncompacted_bytes += p.sz();
+        for res in keys {
+            let (key, old_p) = res?;
+            self.uncompacted_bytes += old_p.sz();
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&key);
+            }
+            self.index.remove(key.clone(), p);
+            self.notify(Event {
+                key,
+                value: None,
+                seq: p.seq,
+            });
+        }
+        self.maintain(p.pos() + p.sz())
+    }
 
-            self.uncompacted_bytes += p.sz;
-            if let Some(old_p) = self.index.get(&e.key) {
-                self.uncompacted_bytes += old_p.value().sz;
+    // send `event` to watchers of its key, and drop watchers gone or with a
+    // full channel, which ends their watches
+    fn notify(&mut self, event: Event) {
+        self.watchers.retain(|watcher| {
+            if watcher.dropped.load(Ordering::Relaxed) {
+                return false;
             }
-            self.index.insert(e.key, p);
-            if self.uncompacted_bytes > COMPACTION_THRESHOLD_BYTES {
-                self.compact()?;
+            if !event.key.starts_with(watcher.prefix.as_str()) {
+                return true;
             }
+            watcher.sender.try_send(event.clone()).is_ok()
+        });
+    }
 
-            Ok(())
-        } else {
-            Err(Error::NonexistentKey)
+    // sync the entry written if asked to, then compact when enough garbage
+    // is collected, otherwise seal the active file of `end` bytes once it is
+    // full
+    fn maintain(&mut self, end: u64) -> Result<()> {
+        if self.options.sync_mode == SyncMode::Always {
+            self.writer.get_ref().sync_data()?;
         }
+        if self.uncompacted_bytes > self.options.compaction_threshold {
+            return self.compact();
+        }
+        if let Index::Disk(index) = &self.index {
+            if end >= self.options.max_file_size {
+                index.seal(self.current_id, end)?;
+                self.current_id += 1;
+                self.writer = new_entry_writer(
+                    &self.dir_path,
+                    self.current_id,
+                    &self.options,
+                    false,
+                )?;
+            }
+        }
+        Ok(())
     }
 
     // compact data to reduce meaningless disk cost
-    fn compact(&mut self) -> Result<()> {
+    pub fn compact(&mut self) -> Result<()> {
+        if let Index::Disk(index) = &self.index {
+            // then all entries are in index files
+            index.seal(self.current_id, self.writer.stream_position()?)?;
+        }
         let compact_id = self.current_id + 1;
         self.current_id += 2;
-        self.writer = new_entry_writer(&self.dir_path, self.current_id)?;
-        self.reader.last_id.store(self.current_id, Ordering::SeqCst);
-
-        let mut compact_writer = new_entry_writer(&self.dir_path, compact_id)?;
-        for p in self.index.iter() {
-            let e = self.reader.locate_entry(p.value())?;
-            if p.value().timestamp == e.timestamp {
-                if e.value.is_empty() {
-                    self.index.remove(p.key());
-                } else {
-                    self.index.insert(
-                        p.key().clone(),
-                        append_entry(&mut compact_writer, self.current_id, &e)?,
-                    );
-                }
+        self.writer = new_entry_writer(
+            &self.dir_path,
+            self.current_id,
+            &self.options,
+            false,
+        )?;
+
+        match &self.index {
+            Index::Memory(map) => {
+                // the index only refers to live entries
+                let positions = map
+                    .iter()
+                    .map(|p| (p.key().clone(), p.value().load()))
+                    .collect();
+                rewrite_entries(
+                    &self.dir_path,
+                    compact_id,
+                    &self.reader,
+                    map,
+                    positions,
+                    &self.options,
+                )?;
+            }
+            Index::Disk(index) => {
+                index.compact(compact_id, &self.reader, &self.options)?
             }
         }
+        self.reader.last_id.store(compact_id, Ordering::SeqCst);
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+
         for file_id in sorted_file_id_list(&self.dir_path)?
             .into_iter()
             .filter(|x| *x < compact_id)
         {
             std::fs::remove_file(data_file_path(&self.dir_path, file_id))?;
+            index::remove_index_file(&self.dir_path, file_id)?;
         }
         self.uncompacted_bytes = 0;
 
@@ -230,12 +905,23 @@ impl DataWriter {
     }
 }
 
+impl From<Entry> for Event {
+    fn from(e: Entry) -> Event {
+        Event {
+            value: (!e.tombstone).then_some(e.value),
+            key: e.key,
+            seq: e.seq,
+        }
+    }
+}
+
 /// A set of readers.
 pub struct DataReader {
     pub dir_path: Arc<PathBuf>,
-    pub readers: RefCell<BTreeMap<u64, BufReader<File>>>,
+    pub readers: RefCell<BTreeMap<u64, DataFile>>,
     // all readers whose id less than last_id is invalid
     pub last_id: Arc<AtomicU64>,
+    pub options: KvStoreOptions,
 }
 
 impl Clone for DataReader {
@@ -244,6 +930,7 @@ impl Clone for DataReader {
             dir_path: self.dir_path.clone(),
             readers: RefCell::new(BTreeMap::new()),
             last_id: self.last_id.clone(),
+            options: self.options.clone(),
         }
     }
 }
@@ -264,48 +951,30 @@ impl DataReader {
 
     fn check_availability<'a>(
         &self,
-        readers: &'a mut std::cell::RefMut<'_, BTreeMap<u64, BufReader<File>>>,
+        readers: &'a mut std::cell::RefMut<'_, BTreeMap<u64, DataFile>>,
         p: &EntryPos,
-    ) -> Result<&'a mut BufReader<File>> {
-        if let btree_map::Entry::Vacant(e) = readers.entry(p.file_id) {
-            let reader = BufReader::new(File::open(data_file_path(
+    ) -> Result<&'a mut DataFile> {
+        if let btree_map::Entry::Vacant(e) = readers.entry(p.file_id()) {
+            e.insert(DataFile::open(
                 &self.dir_path,
-                p.file_id,
-            ))?);
-            e.insert(reader);
+                p.file_id(),
+                &self.options,
+            )?);
         }
-        let reader = readers.get_mut(&p.file_id).unwrap();
-        reader.seek(SeekFrom::Start(p.pos))?;
-        Ok(reader)
+        let file = readers.get_mut(&p.file_id()).unwrap();
+        file.reader.seek(SeekFrom::Start(p.pos()))?;
+        Ok(file)
     }
 
     pub fn locate_value(&self, p: &EntryPos) -> Result<(i64, String)> {
-        self.remove_redundancy();
-        let mut readers = self.readers.borrow_mut();
-        let reader = self.check_availability(&mut readers, p)?;
-
-        let mut i64_buf = [0; (i64::BITS as usize) / 8];
-        reader.read_exact(&mut i64_buf)?;
-        let timestamp = i64::from_ne_bytes(i64_buf);
-
-        let mut usize_buf = [0; (usize::BITS as usize) / 8];
-        reader.read_exact(&mut usize_buf)?;
-        let key_len = usize::from_ne_bytes(usize_buf);
-        reader.read_exact(&mut usize_buf)?;
-        let value_len = usize::from_ne_bytes(usize_buf);
-
-        // skip key
-        reader.seek(SeekFrom::Current(key_len as i64))?;
-        let mut value = vec![0; value_len];
-        reader.read_exact(value.as_mut())?;
-
-        Ok((timestamp, String::from_utf8(value)?))
+        let e = self.locate_entry(p)?;
+        Ok((e.timestamp, e.value))
     }
 
-    fn locate_entry(&self, p: &EntryPos) -> Result<Entry> {
+    pub fn locate_entry(&self, p: &EntryPos) -> Result<Entry> {
         self.remove_redundancy();
         let mut readers = self.readers.borrow_mut();
-        let reader = self.check_availability(&mut readers, p)?;
-        read_entry(reader)
+        let file = self.check_availability(&mut readers, p)?;
+        file.read_entry()
     }
 }
@@ -0,0 +1,65 @@
+use crate::{Error, KvStore, Result, Transaction};
+use std::collections::{BTreeMap, HashMap};
+
+/// Transaction of a [`KvStore`], buffering writes until it commits.
+pub struct KvTransaction<'a> {
+    store: &'a KvStore,
+    // sequence numbers of entries first read for inner keys, `None` for
+    // absent keys
+    reads: HashMap<String, Option<u64>>,
+    // values to write for inner keys, `None` to remove
+    writes: BTreeMap<String, Option<String>>,
+    // a key is changed after it is read, so the transaction must retry
+    pub conflict: bool,
+}
+
+impl KvTransaction<'_> {
+    pub fn new(store: &KvStore) -> KvTransaction<'_> {
+        KvTransaction {
+            store,
+            reads: HashMap::new(),
+            writes: BTreeMap::new(),
+            conflict: false,
+        }
+    }
+
+    /// Keys read and values written, see `DataWriter::commit`.
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts(
+        self,
+    ) -> (
+        HashMap<String, Option<u64>>,
+        BTreeMap<String, Option<String>>,
+    ) {
+        (self.reads, self.writes)
+    }
+}
+
+impl Transaction for KvTransaction<'_> {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.store.inner_key(&key);
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let found = self.store.lookup(key.clone())?;
+        let seq = found.as_ref().map(|(seq, _)| *seq);
+        if *self.reads.entry(key).or_insert(seq) != seq {
+            self.conflict = true;
+            return Err(Error::Message("transaction conflict".to_owned()));
+        }
+        Ok(found.map(|(_, value)| value))
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.writes.insert(self.store.inner_key(&key), Some(value));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(Error::NonexistentKey);
+        }
+        self.writes.insert(self.store.inner_key(&key), None);
+        Ok(())
+    }
+}
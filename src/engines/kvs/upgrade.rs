@@ -0,0 +1,176 @@
+use crate::{
+    engines::kvs::{
+        options::KvStoreOptions,
+        store::{self, Entry},
+    },
+    Result,
+};
+use std::{
+    collections::{hash_map, BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+// | timestamp | key_sz | value_sz |, in native endian and followed by key and
+// value, where an empty value removes the key
+const LEGACY_HEADER_SIZE: u64 = 8 + 2 * (usize::BITS as u64 / 8);
+
+// where the live value of a key is in a legacy file
+struct LegacyValue {
+    file_id: u64,
+    pos: u64,
+    len: usize,
+    timestamp: i64,
+}
+
+/// Rewrite data files in `dir` written by versions without file headers into
+/// a new data file with `options`, and remove them.
+///
+/// Return the number of keys written, which is 0 if there is no legacy file.
+pub fn upgrade(dir: &Path, options: &KvStoreOptions) -> Result<u64> {
+    let id_list = store::sorted_file_id_list(dir)?;
+    let mut legacy = Vec::new();
+    for &file_id in &id_list {
+        if is_legacy(dir, file_id)? {
+            legacy.push(file_id);
+        }
+    }
+    let Some(&last_legacy) = legacy.last() else {
+        return Ok(0);
+    };
+
+    // a file in the current format after legacy ones is only written by an
+    // upgrade which stopped before removing them
+    let mut count = 0;
+    if id_list.last() == Some(&last_legacy) {
+        // replay entries like the previous version did on open
+        let mut values = BTreeMap::new();
+        for &file_id in &legacy {
+            read_legacy(dir, file_id, &mut values)?;
+        }
+
+        // the new file only appears once it is complete
+        let file_id = last_legacy + 1;
+        let temp_dir = dir.join("upgrade");
+        if temp_dir.exists() {
+            std::fs::remove_dir_all(&temp_dir)?;
+        }
+        std::fs::create_dir_all(&temp_dir)?;
+        let mut writer =
+            store::new_entry_writer(&temp_dir, file_id, options, false)?;
+        let mut readers = HashMap::new();
+        for (key, value) in values {
+            let reader = match readers.entry(value.file_id) {
+                hash_map::Entry::Occupied(e) => e.into_mut(),
+                hash_map::Entry::Vacant(e) => e.insert(BufReader::new(
+                    File::open(store::data_file_path(dir, value.file_id))?,
+                )),
+            };
+            reader.seek(SeekFrom::Start(value.pos))?;
+            let mut buf = vec![0; value.len];
+            reader.read_exact(&mut buf)?;
+
+            count += 1;
+            let mut e =
+                Entry::new(count, format!("\0{key}"), String::from_utf8(buf)?);
+            e.timestamp = value.timestamp;
+            store::append_entry(&mut writer, file_id, &e, options)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        std::fs::rename(
+            store::data_file_path(&temp_dir, file_id),
+            store::data_file_path(dir, file_id),
+        )?;
+        std::fs::remove_dir(&temp_dir)?;
+    }
+
+    for file_id in legacy {
+        std::fs::remove_file(store::data_file_path(dir, file_id))?;
+    }
+    Ok(count)
+}
+
+/// Whether the data file `data-{file_id}` is written by versions before file
+/// headers, which is told by its first entry.
+pub fn is_legacy(dir: &Path, file_id: u64) -> Result<bool> {
+    let file = File::open(store::data_file_path(dir, file_id))?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 4];
+    // an empty file is created by the current version before its header
+    if len == 0
+        || reader.read_exact(&mut magic).is_ok() && magic == store::FILE_MAGIC
+    {
+        return Ok(false);
+    }
+    reader.rewind()?;
+    Ok(read_legacy_entry(&mut reader, 0, len)?.is_some())
+}
+
+// record the live value of every key in the legacy file `data-{file_id}`,
+// stopping at the first incomplete entry like the previous version
+fn read_legacy(
+    dir: &Path,
+    file_id: u64,
+    values: &mut BTreeMap<String, LegacyValue>,
+) -> Result<()> {
+    let file = File::open(store::data_file_path(dir, file_id))?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut pos = 0;
+    while let Some((timestamp, key, value_len, end)) =
+        read_legacy_entry(&mut reader, pos, len)?
+    {
+        if value_len == 0 {
+            values.remove(&key);
+        } else {
+            let value = LegacyValue {
+                file_id,
+                pos: end - value_len as u64,
+                len: value_len,
+                timestamp,
+            };
+            values.insert(key, value);
+        }
+        reader.seek_relative(value_len as i64)?;
+        pos = end;
+    }
+    Ok(())
+}
+
+// read the timestamp, key and value length of the legacy entry at `pos` of a
+// file of `len` bytes, and where it ends, leaving the reader at its value, or
+// `None` if it is incomplete
+fn read_legacy_entry(
+    reader: &mut BufReader<File>,
+    pos: u64,
+    len: u64,
+) -> Result<Option<(i64, String, usize, u64)>> {
+    if pos + LEGACY_HEADER_SIZE > len {
+        return Ok(None);
+    }
+    let mut header = [0; LEGACY_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    let size = usize::BITS as usize / 8;
+    let usize_at = |i: usize| {
+        usize::from_ne_bytes(header[i..i + size].try_into().unwrap())
+    };
+    let timestamp = i64::from_ne_bytes(header[..8].try_into().unwrap());
+    let key_len = usize_at(8);
+    let value_len = usize_at(8 + size);
+
+    let end = (key_len as u64)
+        .checked_add(value_len as u64)
+        .and_then(|sz| sz.checked_add(pos + LEGACY_HEADER_SIZE));
+    let Some(end) = end.filter(|end| *end <= len) else {
+        return Ok(None);
+    };
+    let mut key = vec![0; key_len];
+    reader.read_exact(&mut key)?;
+    Ok(String::from_utf8(key)
+        .ok()
+        .map(|key| (timestamp, key, value_len, end)))
+}
@@ -1,13 +1,32 @@
-use crate::{Error, KvsEngine, Result};
-use std::ops::Deref;
+use crate::{Error, Event, KvsEngine, Result, Scan, Transaction, Watch};
+use crossbeam::channel::RecvTimeoutError;
+use sled::transaction::{
+    ConflictableTransactionError, TransactionError, TransactionalTree,
+    UnabortableTransactionError,
+};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ops::{Deref, RangeBounds},
+    sync::mpsc,
+};
 
 /// implement `KvsEngine` for `sled` for benchmarking
-pub struct SledStore(sled::Db);
+#[derive(Clone)]
+pub struct SledStore {
+    db: sled::Db,
+    // the default tree, or the one of a keyspace
+    tree: sled::Tree,
+}
 
 impl SledStore {
     /// Open a Db with a default configuration at the specified directory.
     pub fn open(path: impl Into<std::path::PathBuf>) -> Result<SledStore> {
-        Ok(SledStore(sled::open(path.into())?))
+        let db = sled::open(path.into())?;
+        Ok(SledStore {
+            tree: (*db).clone(),
+            db,
+        })
     }
 }
 
@@ -15,13 +34,7 @@ impl Deref for SledStore {
     type Target = sled::Tree;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl Clone for SledStore {
-    fn clone(&self) -> Self {
-        SledStore(self.0.clone())
+        &self.tree
     }
 }
 
@@ -44,4 +57,226 @@ impl KvsEngine for SledStore {
         self.flush()?;
         Ok(())
     }
+
+    /// Set keys to values of `pairs` with one batch.
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_str(), value.into_bytes());
+        }
+        self.apply_batch(batch)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Remove all keys in `range` with one batch.
+    fn remove_range(&self, range: impl RangeBounds<String>) -> Result<()> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut batch = sled::Batch::default();
+        for res in self.tree.range::<String, _>(range) {
+            batch.remove(res?.0);
+        }
+        self.apply_batch(batch)?;
+        self.flush()?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.tree.clear()?;
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Apply `operator` with [`sled::Tree::update_and_fetch`], which calls
+    /// it again if the value is changed concurrently.
+    fn merge<F>(&self, key: String, mut operator: F) -> Result<Option<String>>
+    where
+        F: FnMut(Option<&str>) -> Result<Option<String>>,
+    {
+        // sled takes an infallible function, so a failure keeps the old value
+        let mut failure = None;
+        let new = self.update_and_fetch(key, |old| {
+            let merged = old
+                .map(std::str::from_utf8)
+                .transpose()
+                .map_err(|e| Error::Message(e.to_string()))
+                .and_then(&mut operator);
+            match merged {
+                Ok(new) => {
+                    failure = None;
+                    new.map(String::into_bytes)
+                }
+                Err(e) => {
+                    failure = Some(e);
+                    old.map(<[u8]>::to_vec)
+                }
+            }
+        })?;
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        self.flush()?;
+        new.map(|ivec| String::from_utf8(ivec.to_vec()))
+            .transpose()
+            .map_err(Error::from)
+    }
+
+    /// Run `f` as a transaction of [`sled::Tree::transaction`], which runs
+    /// it again on a conflict.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut dyn Transaction) -> Result<T>,
+    {
+        // sled takes a `Fn`, which is never called reentrantly
+        let f = RefCell::new(f);
+        let result = self.tree.transaction(|tree| {
+            let mut tx = SledTransaction {
+                tree,
+                failure: None,
+            };
+            let result = (f.borrow_mut())(&mut tx);
+            if let Some(e) = tx.failure {
+                return Err(e.into());
+            }
+            result.map_err(ConflictableTransactionError::Abort)
+        });
+        match result {
+            Ok(value) => {
+                self.flush()?;
+                Ok(value)
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<Scan> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(Box::new(self.range::<String, _>(range).map(|res| {
+            let (key, value) = res?;
+            Ok((
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            ))
+        })))
+    }
+
+    /// Watch changes of keys starting with `prefix` from now on.
+    ///
+    /// `sled` does not number its writes, so events are numbered from 1
+    /// within each watch.
+    fn watch(&self, prefix: String) -> Result<Watch> {
+        let mut seq = 0;
+        let mut subscriber = self.watch_prefix(prefix);
+        Ok(Watch::new(move |timeout| {
+            let event = match timeout {
+                Some(timeout) => {
+                    subscriber.next_timeout(timeout).map_err(|e| match e {
+                        mpsc::RecvTimeoutError::Timeout => {
+                            RecvTimeoutError::Timeout
+                        }
+                        mpsc::RecvTimeoutError::Disconnected => {
+                            RecvTimeoutError::Disconnected
+                        }
+                    })?
+                }
+                None => {
+                    subscriber.next().ok_or(RecvTimeoutError::Disconnected)?
+                }
+            };
+            seq += 1;
+            Ok(convert_event(event, seq))
+        }))
+    }
+
+    /// Get a handle of the keyspace `name`, which is a tree opened by
+    /// [`sled::Db::open_tree`].
+    fn keyspace(&self, name: &str) -> Result<SledStore> {
+        // the default tree is named `__sled__default`
+        if name.is_empty() || name.starts_with("__sled__") {
+            return Err(Error::Message(format!(
+                "invalid keyspace name {name:?}"
+            )));
+        }
+        Ok(SledStore {
+            db: self.db.clone(),
+            tree: self.db.open_tree(name)?,
+        })
+    }
+
+    /// Names of trees other than the default one, including empty ones.
+    fn keyspaces(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for name in self.db.tree_names() {
+            if !name.starts_with(b"__sled__") {
+                names.push(String::from_utf8(name.to_vec())?);
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    fn stats(&self) -> Result<BTreeMap<String, u64>> {
+        let size = self.db.size_on_disk()?;
+        Ok(BTreeMap::from([("size_on_disk".to_owned(), size)]))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+// convert `event` of `sled` to the `seq`th event of a watch
+fn convert_event(event: sled::Event, seq: u64) -> Result<Event> {
+    let (key, value) = match event {
+        sled::Event::Insert { key, value } => {
+            (key, Some(String::from_utf8(value.to_vec())?))
+        }
+        sled::Event::Remove { key } => (key, None),
+    };
+    Ok(Event {
+        key: String::from_utf8(key.to_vec())?,
+        value,
+        seq,
+    })
+}
+
+struct SledTransaction<'a> {
+    tree: &'a TransactionalTree,
+    // conflict or storage error to hand back to sled, which retries on a
+    // conflict
+    failure: Option<UnabortableTransactionError>,
+}
+
+impl SledTransaction<'_> {
+    fn fail(&mut self, e: UnabortableTransactionError) -> Error {
+        let message = e.to_string();
+        self.failure.get_or_insert(e);
+        Error::Message(message)
+    }
+}
+
+impl Transaction for SledTransaction<'_> {
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.tree.get(key).map_err(|e| self.fail(e))?;
+        Ok(value
+            .map(|ivec| String::from_utf8(ivec.to_vec()))
+            .transpose()?)
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.tree
+            .insert(key.as_str(), value.into_bytes())
+            .map_err(|e| self.fail(e))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.tree
+            .remove(key.as_str())
+            .map_err(|e| self.fail(e))?
+            .ok_or(Error::NonexistentKey)?;
+        Ok(())
+    }
 }
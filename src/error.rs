@@ -15,8 +15,14 @@ pub enum Error {
     ParseInt(num::ParseIntError),
     /// No such a key
     NonexistentKey,
+    /// Damaged data file
+    Corrupted(String),
     /// from Sled
     Sled(sled::Error),
+    /// from rustls
+    Tls(rustls::Error),
+    /// Request not permitted to the user
+    Denied(String),
 }
 
 impl From<io::Error> for Error {
@@ -49,6 +55,12 @@ impl From<sled::Error> for Error {
     }
 }
 
+impl From<rustls::Error> for Error {
+    fn from(value: rustls::Error) -> Self {
+        Error::Tls(value)
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -58,7 +70,14 @@ impl fmt::Display for Error {
             Self::Utf8(e) => write!(f, "{}", e),
             Self::ParseInt(e) => write!(f, "{}", e),
             Self::NonexistentKey => write!(f, "No such a key"),
+            Self::Corrupted(message) => {
+                write!(f, "Data file is corrupted: {}", message)
+            }
             Self::Sled(e) => write!(f, "{}", e),
+            Self::Tls(e) => write!(f, "{}", e),
+            Self::Denied(message) => {
+                write!(f, "Permission denied: {}", message)
+            }
         }
     }
 }
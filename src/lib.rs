@@ -1,15 +1,31 @@
 //! `kvs` is a simple key-value store engine written in Rust.
 
+/// Users of `kvs-server` and their access to keys.
+pub mod auth;
+/// Client of `kvs-server`.
+pub mod client;
 /// Protocol used for communicating between client and server.
 pub mod common;
+mod dump;
 mod engines;
 mod error;
+mod migrate;
+/// Server of a store for `kvs-client`, Redis clients and HTTP clients.
+pub mod server;
 /// Thread pool implementations
 pub mod thread_pool;
+/// TLS of connections between client and server.
+pub mod tls;
 
 // re-export names with pub use
-pub use crate::engines::{KvStore, KvsEngine, SledStore};
+pub use crate::dump::{dump, load};
+pub use crate::engines::{
+    CacheStats, Codec, Damage, EncryptionKey, EntryInfo, Event, IndexMode,
+    KvStore, KvStoreOptions, KvsEngine, Scan, SledStore, SyncMode, Transaction,
+    VerifyReport, Watch,
+};
 pub use crate::error::Error;
+pub use crate::migrate::migrate;
 
 /// to simplify concrete implementations
 pub type Result<T> = std::result::Result<T, Error>;
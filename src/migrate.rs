@@ -0,0 +1,106 @@
+use crate::{Error, KvStore, KvStoreOptions, KvsEngine, Result, SledStore};
+use std::path::{Path, PathBuf};
+
+/// Move data in `dir` persisted with engine `from` into engine `to`, and
+/// return the number of migrated key-value pairs.
+///
+/// All live keys of every keyspace are copied into a sibling directory
+/// `{dir}.{to}` first.
+/// After the key counts are verified, `dir` is kept as `{dir}.{from}.bak`,
+/// the new directory takes its place and its `identity` is rewritten.
+///
+/// Data of `kvs` is read or written with `options`, so an encrypted store
+/// stays encrypted.
+///
+/// Nothing else should access `dir` during the migration.
+pub fn migrate(
+    dir: &Path,
+    from: &str,
+    to: &str,
+    options: &KvStoreOptions,
+) -> Result<u64> {
+    if from == to {
+        return Err(Error::Message(format!("data is already in `{to}`")));
+    }
+    // data without `identity` is only ever written by `kvs`
+    let id =
+        std::fs::read_to_string(dir.join("identity")).unwrap_or("kvs".into());
+    if id != from {
+        return Err(Error::Message(format!(
+            "data in {} is persisted with `{id}`, not `{from}`",
+            dir.display()
+        )));
+    }
+
+    let target_dir = sibling(dir, to);
+    let backup_dir = sibling(dir, &format!("{from}.bak"));
+    for path in [&target_dir, &backup_dir] {
+        if path.exists() {
+            return Err(Error::Message(format!(
+                "{} already exists, remove it before migration",
+                path.display()
+            )));
+        }
+    }
+
+    let count = match (from, to) {
+        ("kvs", "sled") => copy(
+            &KvStore::open_with(dir, options.clone())?,
+            &SledStore::open(&target_dir)?,
+        )?,
+        ("sled", "kvs") => copy(
+            &SledStore::open(dir)?,
+            &KvStore::open_with(&target_dir, options.clone())?,
+        )?,
+        _ => {
+            return Err(Error::Message(format!(
+                "unable to migrate from `{from}` to `{to}`"
+            )))
+        }
+    };
+
+    std::fs::write(target_dir.join("identity"), to)?;
+    std::fs::rename(dir, &backup_dir)?;
+    std::fs::rename(&target_dir, dir)?;
+    Ok(count)
+}
+
+// `{dir}.{suffix}` next to `dir`
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{suffix}"));
+    dir.with_file_name(name)
+}
+
+// copy all live keys of every keyspace
+fn copy<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<u64> {
+    let mut count = copy_keys(source, target)?;
+    for name in source.keyspaces()? {
+        count += copy_keys(&source.keyspace(&name)?, &target.keyspace(&name)?)?;
+    }
+    Ok(count)
+}
+
+// copy all live keys of a keyspace and verify both sides hold the same
+// number of keys
+fn copy_keys<S: KvsEngine, T: KvsEngine>(
+    source: &S,
+    target: &T,
+) -> Result<u64> {
+    let mut count = 0;
+    for pair in source.scan(..)? {
+        let (key, value) = pair?;
+        target.set(key, value)?;
+        count += 1;
+    }
+
+    let copied = target
+        .scan(..)?
+        .try_fold(0, |n, pair| pair.map(|_| n + 1))?;
+    if copied != count {
+        return Err(Error::Message(format!(
+            "{count} keys are read, but {copied} keys are written"
+        )));
+    }
+    Ok(count)
+}
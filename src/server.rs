@@ -0,0 +1,691 @@
+use crate::{
+    auth::{Credentials, User},
+    common::{
+        binary, ErrorCode, Request, Response, Tagged, TxOp, PROTOCOL_VERSION,
+    },
+    thread_pool::ThreadPool,
+    tls::Stream,
+    Error, KvsEngine, Result, Transaction,
+};
+use rustls::ServerConfig;
+use serde_json::{de::IoRead, Deserializer, StreamDeserializer};
+use slog::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    net::{
+        self, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream,
+        ToSocketAddrs,
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+mod http;
+mod redis;
+
+// how often a watch checks for shutdown and whether the watcher is gone
+// while no key changes
+const WATCH_POLL: Duration = Duration::from_millis(200);
+
+/// Server of a store for clients of [`crate::client`], and optionally for
+/// Redis clients and HTTP clients.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// # use kvs::{server::KvsServer, thread_pool::*, KvStore};
+/// # use std::thread;
+/// let logger = slog::Logger::root(slog::Discard, slog::o!());
+/// let store = KvStore::open("data")?;
+/// let pool = NaiveThreadPool::new(4)?;
+/// let server = KvsServer::new("kvs", store, pool, logger);
+/// let shutdown = server.shutdown_handle();
+/// let serving = thread::spawn(move || server.run("127.0.0.1:4000"));
+/// // ...
+/// shutdown.shutdown();
+/// serving.join().unwrap()?;
+/// # Ok::<(), kvs::Error>(())
+/// ```
+pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+    logger: slog::Logger,
+    engine: &'static str,
+    // address to serve Redis clients at, if any
+    resp_addr: Option<String>,
+    // address to serve HTTP clients at, if any
+    http_addr: Option<String>,
+    // configuration to serve clients over TLS, if any
+    tls: Option<Arc<ServerConfig>>,
+    // users allowed to access keys, or anyone if absent
+    credentials: Option<Arc<Credentials>>,
+    // time for connections to finish on shutdown
+    drain_timeout: Duration,
+    shutdown: Shutdown,
+    store: E,
+    pool: P,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Sync> KvsServer<E, P> {
+    /// Create a server of `store` with the engine named `engine`, serving
+    /// connections in `pool`.
+    pub fn new(
+        engine: &'static str,
+        store: E,
+        pool: P,
+        logger: slog::Logger,
+    ) -> KvsServer<E, P> {
+        KvsServer {
+            logger,
+            engine,
+            resp_addr: None,
+            http_addr: None,
+            tls: None,
+            credentials: None,
+            drain_timeout: Duration::from_secs(10),
+            shutdown: Shutdown::default(),
+            store,
+            pool,
+        }
+    }
+
+    /// Also serve Redis clients with RESP2 at `addr`.
+    pub fn resp_addr(mut self, addr: impl Into<String>) -> KvsServer<E, P> {
+        self.resp_addr = Some(addr.into());
+        self
+    }
+
+    /// Also serve the HTTP/JSON gateway at `addr`, in plaintext, so it can not
+    /// be served with [`KvsServer::credentials`].
+    pub fn http_addr(mut self, addr: impl Into<String>) -> KvsServer<E, P> {
+        self.http_addr = Some(addr.into());
+        self
+    }
+
+    /// Serve clients other than HTTP ones over TLS with `config`, see
+    /// [`crate::tls::server_config`].
+    pub fn tls(mut self, config: Arc<ServerConfig>) -> KvsServer<E, P> {
+        self.tls = Some(config);
+        self
+    }
+
+    /// Require clients to authenticate as users of `credentials`, whose ACLs
+    /// are enforced.
+    pub fn credentials(mut self, credentials: Credentials) -> KvsServer<E, P> {
+        self.credentials = Some(Arc::new(credentials));
+        self
+    }
+
+    /// Set how long connections are waited for on shutdown before they are
+    /// cut off, 10 seconds by default.
+    pub fn drain_timeout(mut self, timeout: Duration) -> KvsServer<E, P> {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Handle to shut down the server from another thread.
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Serve clients at `addr` until it is shut down, then wait for
+    /// connections to finish and sync the store.
+    pub fn run<A>(&self, addr: A) -> Result<()>
+    where
+        A: ToSocketAddrs + fmt::Display,
+    {
+        // passwords of the gateway would be sent in plaintext
+        if self.http_addr.is_some() && self.credentials.is_some() {
+            return Err(Error::Message(
+                "the HTTP gateway has no TLS to authenticate users over"
+                    .to_owned(),
+            ));
+        }
+        let listener = self.bind(&addr)?;
+        info!(self.logger, "server starts at {addr}.");
+        let resp_listener = match &self.resp_addr {
+            Some(addr) => {
+                let listener = self.bind(addr)?;
+                info!(self.logger, "RESP listener starts at {addr}.");
+                Some(listener)
+            }
+            None => None,
+        };
+        let http_server = match &self.http_addr {
+            Some(addr) => {
+                let server = tiny_http::Server::http(addr).map_err(|e| {
+                    Error::Message(format!(
+                        "unable to create HTTP listener at {addr}: {e}"
+                    ))
+                })?;
+                let server = Arc::new(server);
+                let unblocked = server.clone();
+                self.shutdown.on_shutdown(move || unblocked.unblock());
+                info!(self.logger, "HTTP gateway starts at {addr}.");
+                Some(server)
+            }
+            None => None,
+        };
+
+        let credentials = self.credentials.clone();
+        let shutdown = self.shutdown.clone();
+        let features = features(
+            self.engine,
+            self.tls.is_some(),
+            self.credentials.is_some(),
+        );
+        thread::scope(|scope| {
+            if let Some(server) = http_server {
+                let store = self.store.clone();
+                let logger = self.logger.clone();
+                let pool = &self.pool;
+                let shutdown = &self.shutdown;
+                let stats = Arc::new(http::HttpStats::new(self.engine));
+                scope.spawn(move || {
+                    http::accept_http(
+                        store, &logger, pool, shutdown, &server, stats,
+                    )
+                });
+            }
+            if let Some(listener) = resp_listener {
+                let store = self.store.clone();
+                let logger = self.logger.clone();
+                let pool = &self.pool;
+                let shutdown = &self.shutdown;
+                let tls = self.tls.clone();
+                let credentials = self.credentials.clone();
+                scope.spawn(move || {
+                    accept(
+                        store,
+                        &logger,
+                        pool,
+                        shutdown,
+                        listener,
+                        tls,
+                        move |store, logger, stream| {
+                            redis::process_resp(
+                                store,
+                                logger,
+                                credentials.as_deref(),
+                                stream,
+                            )
+                        },
+                    )
+                });
+            }
+            accept(
+                self.store.clone(),
+                &self.logger,
+                &self.pool,
+                &self.shutdown,
+                listener,
+                self.tls.clone(),
+                move |store, logger, stream| {
+                    process(
+                        store,
+                        logger,
+                        &shutdown,
+                        &features,
+                        credentials.as_deref(),
+                        stream,
+                    )
+                },
+            );
+        });
+
+        info!(self.logger, "server stops accepting, draining connections.");
+        let left = self.shutdown.drain(self.drain_timeout);
+        if left > 0 {
+            warn!(self.logger, "cut off {left} connections at the deadline.");
+        }
+        self.store.sync()?;
+        info!(self.logger, "server stops.");
+        Ok(())
+    }
+
+    // bind a listener at `addr`, which is woken up on shutdown by connecting
+    // to it
+    fn bind<A>(&self, addr: &A) -> Result<TcpListener>
+    where
+        A: ToSocketAddrs + fmt::Display,
+    {
+        let listener = TcpListener::bind(addr).map_err(|e| {
+            Error::Message(format!(
+                "unable to create TCP listener at {addr}: {e}"
+            ))
+        })?;
+        let mut local = listener.local_addr()?;
+        if local.ip().is_unspecified() {
+            match local {
+                SocketAddr::V4(_) => local.set_ip(Ipv4Addr::LOCALHOST.into()),
+                SocketAddr::V6(_) => local.set_ip(Ipv6Addr::LOCALHOST.into()),
+            }
+        }
+        self.shutdown.on_shutdown(move || {
+            let _ = TcpStream::connect(local);
+        });
+        Ok(listener)
+    }
+}
+
+/// Handle to shut down a [`KvsServer`] gracefully.
+///
+/// The server stops accepting connections and reading requests of active
+/// ones, but answers requests being served, then syncs its store and
+/// returns from [`KvsServer::run`].
+#[derive(Clone, Default)]
+pub struct Shutdown(Arc<ShutdownState>);
+
+#[derive(Default)]
+struct ShutdownState {
+    stopping: AtomicBool,
+    // to unblock threads accepting connections
+    wakers: Mutex<Vec<Box<dyn Fn() + Send>>>,
+    connections: Mutex<Connections>,
+    // notified when a connection ends
+    ended: Condvar,
+}
+
+// connections being served, with their sockets if any
+#[derive(Default)]
+struct Connections {
+    last_id: u64,
+    streams: HashMap<u64, Option<TcpStream>>,
+}
+
+impl Shutdown {
+    /// Ask the server to shut down, without waiting for it.
+    pub fn shutdown(&self) {
+        if self.0.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for wake in self.0.wakers.lock().unwrap().iter() {
+            wake();
+        }
+        let connections = self.0.connections.lock().unwrap();
+        for stream in connections.streams.values().flatten() {
+            let _ = stream.shutdown(net::Shutdown::Read);
+        }
+    }
+
+    /// Whether the server is asked to shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.0.stopping.load(Ordering::SeqCst)
+    }
+
+    // call `wake` on shutdown, or now if it is shut down already
+    fn on_shutdown(&self, wake: impl Fn() + Send + 'static) {
+        let mut wakers = self.0.wakers.lock().unwrap();
+        if self.is_shutdown() {
+            wake();
+        }
+        wakers.push(Box::new(wake));
+    }
+
+    // track a connection being served until the guard is dropped, and stop
+    // reading requests from `stream` on shutdown
+    fn enter(&self, stream: Option<TcpStream>) -> Connection {
+        let mut connections = self.0.connections.lock().unwrap();
+        if self.is_shutdown() {
+            if let Some(stream) = &stream {
+                let _ = stream.shutdown(net::Shutdown::Read);
+            }
+        }
+        connections.last_id += 1;
+        let id = connections.last_id;
+        connections.streams.insert(id, stream);
+        Connection {
+            shutdown: self.clone(),
+            id,
+        }
+    }
+
+    // wait for connections to end within `timeout`, then close the others,
+    // returning how many are closed
+    fn drain(&self, timeout: Duration) -> usize {
+        let connections = self.0.connections.lock().unwrap();
+        let (connections, _) = self
+            .0
+            .ended
+            .wait_timeout_while(connections, timeout, |connections| {
+                !connections.streams.is_empty()
+            })
+            .unwrap();
+        for stream in connections.streams.values().flatten() {
+            let _ = stream.shutdown(net::Shutdown::Both);
+        }
+        connections.streams.len()
+    }
+}
+
+// connection tracked by `Shutdown` while it is alive
+struct Connection {
+    shutdown: Shutdown,
+    id: u64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let state = &self.shutdown.0;
+        state.connections.lock().unwrap().streams.remove(&self.id);
+        state.ended.notify_all();
+    }
+}
+
+// features advertised to clients saying hello
+fn features(engine: &str, tls: bool, auth: bool) -> Vec<String> {
+    let mut features = vec![
+        format!("engine:{engine}"),
+        "encoding:json".to_owned(),
+        "encoding:binary".to_owned(),
+    ];
+    if tls {
+        features.push("tls".to_owned());
+    }
+    if auth {
+        features.push("auth".to_owned());
+    }
+    features
+}
+
+// serve connections of `listener` by `process` in `pool`, over TLS if `tls`
+// is given, until shutdown
+fn accept<E, P, F>(
+    store: E,
+    logger: &slog::Logger,
+    pool: &P,
+    shutdown: &Shutdown,
+    listener: TcpListener,
+    tls: Option<Arc<ServerConfig>>,
+    process: F,
+) where
+    E: KvsEngine,
+    P: ThreadPool,
+    F: Fn(E, &slog::Logger, Stream) -> Result<()> + Clone + Send + 'static,
+{
+    for stream in listener.incoming() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        match stream {
+            Ok(stream) => {
+                let store = store.clone();
+                let logger = logger.clone();
+                let process = process.clone();
+                let tls = tls.clone();
+                let connection = shutdown.enter(stream.try_clone().ok());
+                let shutdown = shutdown.clone();
+                pool.spawn(move || {
+                    let _connection = connection;
+                    // handshakes are done in the pool, not to block accepting
+                    let serve = || {
+                        // responses are small and flushed at once, so do not
+                        // delay them
+                        stream.set_nodelay(true)?;
+                        let stream = match tls {
+                            Some(config) => Stream::accept(stream, config)?,
+                            None => Stream::plain(stream),
+                        };
+                        process(store, &logger, stream)
+                    };
+                    match serve() {
+                        Ok(()) => {}
+                        // such as a TLS stream cut without closing it
+                        Err(e) if shutdown.is_shutdown() => {
+                            debug!(logger, "connection ends on shutdown: {e}");
+                        }
+                        Err(e) => error!(logger, "failed to serve client: {e}"),
+                    }
+                });
+            }
+            Err(e) => error!(logger, "failed at connection: {e}"),
+        }
+    }
+}
+
+fn process<E: KvsEngine>(
+    store: E,
+    logger: &slog::Logger,
+    shutdown: &Shutdown,
+    features: &[String],
+    credentials: Option<&Credentials>,
+    stream: Stream,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = BufWriter::new(stream);
+    // user authenticated on the connection
+    let mut user = None;
+
+    // binary clients open with its magic, and JSON ones with an object
+    let binary = reader.fill_buf()?.first() == Some(&binary::MAGIC[0]);
+    let requests = if binary {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != binary::MAGIC {
+            return Err(Error::Message("unknown protocol".into()));
+        }
+        writer.write_all(&binary::MAGIC)?;
+        writer.flush()?;
+        Requests::Binary(reader)
+    } else {
+        Requests::Json(Deserializer::from_reader(reader).into_iter())
+    };
+
+    // responses carry IDs of their requests, and are sent in order
+    for request in requests {
+        let Tagged { id, body: request } = request?;
+        if let Err(e) = authorize(credentials, user, &request) {
+            send(&mut writer, binary, id, &Response::error(&e))?;
+            continue;
+        }
+        let response = match request {
+            Request::Hello { version, .. } if version != PROTOCOL_VERSION => {
+                let response = Response::Error {
+                    code: ErrorCode::Unsupported,
+                    message: format!(
+                        "client speaks protocol version {version}, but \
+                        server speaks {PROTOCOL_VERSION}"
+                    ),
+                };
+                send(&mut writer, binary, id, &response)?;
+                debug!(logger, "reject client of version {version}");
+                return Ok(());
+            }
+            Request::Hello { .. } => Response::Hello {
+                version: PROTOCOL_VERSION,
+                features: features.to_vec(),
+            },
+            Request::Auth {
+                user: name,
+                password,
+            } => match credentials {
+                Some(credentials) => {
+                    match credentials.authenticate(&name, &password) {
+                        Ok(authenticated) => {
+                            debug!(logger, "authenticate user {name}");
+                            user = Some(authenticated);
+                            Response::Ok
+                        }
+                        Err(e) => Response::error(&e),
+                    }
+                }
+                None => Response::Error {
+                    code: ErrorCode::Invalid,
+                    message: "authentication is not enabled".to_owned(),
+                },
+            },
+            Request::Watch { prefix, namespace } => {
+                let watch = keyspace(&store, namespace)
+                    .and_then(|store| store.watch(prefix));
+                let mut events = match watch {
+                    Ok(watch) => watch,
+                    Err(e) => {
+                        send(&mut writer, binary, id, &Response::error(&e))?;
+                        continue;
+                    }
+                };
+                // wake up now and then to end the watch on shutdown, or
+                // when the watcher is gone without any event to send
+                while !events.is_ended() {
+                    let Some(event) = events.next_timeout(WATCH_POLL) else {
+                        if shutdown.is_shutdown()
+                            || writer.get_ref().is_closed()?
+                        {
+                            debug!(logger, "watcher is gone");
+                            break;
+                        }
+                        continue;
+                    };
+                    let response = Response::Event(event?);
+                    if send(&mut writer, binary, id, &response).is_err() {
+                        debug!(logger, "watcher is gone");
+                        break;
+                    }
+                    debug!(logger, "send response {:?}", response);
+                }
+                return Ok(());
+            }
+            request => {
+                serve(&store, request).unwrap_or_else(|e| Response::error(&e))
+            }
+        };
+
+        send(&mut writer, binary, id, &response)?;
+        debug!(logger, "send response {:?}", response);
+    }
+
+    Ok(())
+}
+
+// check the access of the user of a connection to `request`, if users are
+// required to authenticate
+fn authorize(
+    credentials: Option<&Credentials>,
+    user: Option<&User>,
+    request: &Request,
+) -> Result<()> {
+    match (credentials, user, request) {
+        (None, ..) | (.., Request::Hello { .. } | Request::Auth { .. }) => {
+            Ok(())
+        }
+        (Some(_), Some(user), request) => user.authorize(request),
+        (Some(_), None, _) => {
+            Err(Error::Denied("authentication required".to_owned()))
+        }
+    }
+}
+
+// requests of a connection in the protocol chosen by the client
+enum Requests {
+    Json(
+        StreamDeserializer<'static, IoRead<BufReader<Stream>>, Tagged<Request>>,
+    ),
+    Binary(BufReader<Stream>),
+}
+
+impl Iterator for Requests {
+    type Item = Result<Tagged<Request>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Requests::Json(requests) => {
+                requests.next().map(|res| res.map_err(Error::from))
+            }
+            Requests::Binary(reader) => {
+                binary::read_request(reader).transpose()
+            }
+        }
+    }
+}
+
+// write `response` to the request of `id` in the protocol of the connection
+fn send(
+    writer: &mut BufWriter<Stream>,
+    binary: bool,
+    id: u64,
+    response: &Response,
+) -> Result<()> {
+    if binary {
+        binary::write_response(writer, id, response)?;
+    } else {
+        let response = Tagged { id, body: response };
+        serde_json::to_writer(&mut *writer, &response)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// do a request other than `Watch`
+fn serve<E: KvsEngine>(store: &E, request: Request) -> Result<Response> {
+    Ok(match request {
+        Request::Set {
+            key,
+            value,
+            namespace,
+        } => {
+            keyspace(store, namespace)?.set(key, value)?;
+            Response::Ok
+        }
+        Request::Get { key, namespace } => {
+            Response::Value(keyspace(store, namespace)?.get(key)?)
+        }
+        Request::Remove { key, namespace } => {
+            keyspace(store, namespace)?.remove(key)?;
+            Response::Ok
+        }
+        Request::MGet { keys, namespace } => {
+            Response::Values(keyspace(store, namespace)?.get_many(keys)?)
+        }
+        Request::MSet { pairs, namespace } => {
+            keyspace(store, namespace)?.set_many(pairs)?;
+            Response::Ok
+        }
+        Request::Incr {
+            key,
+            delta,
+            namespace,
+        } => Response::Integer(
+            keyspace(store, namespace)?.increment(key, delta)?,
+        ),
+        Request::Transaction { ops, namespace } => Response::Values(
+            keyspace(store, namespace)?.transaction(|tx| run(tx, &ops))?,
+        ),
+        Request::Hello { .. }
+        | Request::Auth { .. }
+        | Request::Watch { .. } => {
+            unreachable!("hellos, auths and watches are served by process")
+        }
+    })
+}
+
+// the keyspace named by `namespace` in a request, or the default one
+fn keyspace<E: KvsEngine>(store: &E, namespace: Option<String>) -> Result<E> {
+    match namespace {
+        Some(name) => store.keyspace(&name),
+        None => Ok(store.clone()),
+    }
+}
+
+// run operations of a transaction request, returning values got
+fn run(tx: &mut dyn Transaction, ops: &[TxOp]) -> Result<Vec<Option<String>>> {
+    let mut values = Vec::new();
+    for op in ops {
+        match op {
+            TxOp::Get { key } => values.push(tx.get(key.clone())?),
+            TxOp::Set { key, value } => tx.set(key.clone(), value.clone())?,
+            TxOp::Remove { key } => tx.remove(key.clone())?,
+            TxOp::Check { key, value } => {
+                if tx.get(key.clone())? != *value {
+                    return Err(Error::Message(format!(
+                        "check failed on key {key}"
+                    )));
+                }
+            }
+        }
+    }
+    Ok(values)
+}
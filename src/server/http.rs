@@ -0,0 +1,216 @@
+use super::{keyspace, Shutdown};
+use crate::{thread_pool::ThreadPool, Error, KvsEngine, Result};
+use serde_json::json;
+use slog::{debug, error};
+use std::{
+    io::Read,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tiny_http::Method;
+
+// longest body accepted as a value, as RESP does for bulk strings
+const MAX_BODY_LEN: u64 = 512 << 20;
+
+// counters of the HTTP gateway
+pub(super) struct HttpStats {
+    engine: &'static str,
+    started: Instant,
+    requests: AtomicU64,
+    // requests answered with a status of 4xx or 5xx
+    errors: AtomicU64,
+}
+
+impl HttpStats {
+    pub(super) fn new(engine: &'static str) -> HttpStats {
+        HttpStats {
+            engine,
+            started: Instant::now(),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+}
+
+// serve requests of the HTTP gateway in `pool` until shutdown
+pub(super) fn accept_http<E: KvsEngine, P: ThreadPool>(
+    store: E,
+    logger: &slog::Logger,
+    pool: &P,
+    shutdown: &Shutdown,
+    server: &tiny_http::Server,
+    stats: Arc<HttpStats>,
+) {
+    for mut request in server.incoming_requests() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        let connection = shutdown.enter(None);
+        let store = store.clone();
+        let logger = logger.clone();
+        let stats = stats.clone();
+        pool.spawn(move || {
+            let _connection = connection;
+            debug!(
+                logger,
+                "receive HTTP {} {}",
+                request.method(),
+                request.url()
+            );
+            let (status, body) = route_http(&store, &mut request, &stats)
+                .unwrap_or_else(|e| {
+                    (http_status(&e), json!({ "error": e.to_string() }))
+                });
+            stats.requests.fetch_add(1, Ordering::Relaxed);
+            if status >= 400 {
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            let response = if body.is_null() {
+                tiny_http::Response::from_data(Vec::new())
+            } else {
+                let header = tiny_http::Header::from_bytes(
+                    "Content-Type",
+                    "application/json",
+                )
+                .unwrap();
+                tiny_http::Response::from_data(body.to_string())
+                    .with_header(header)
+            };
+            if let Err(e) = request.respond(response.with_status_code(status)) {
+                error!(logger, "failed to serve HTTP client: {e}");
+            }
+        });
+    }
+}
+
+// status of a request failed with `e`, in line with `Response::error`
+fn http_status(e: &Error) -> u16 {
+    match e {
+        Error::NonexistentKey => 404,
+        Error::Message(_) | Error::ParseInt(_) | Error::Utf8(_) => 400,
+        Error::Denied(_) => 403,
+        _ => 500,
+    }
+}
+
+// serve an HTTP request by its route, returning the status and JSON body,
+// which is null for no content
+fn route_http<E: KvsEngine>(
+    store: &E,
+    request: &mut tiny_http::Request,
+    stats: &HttpStats,
+) -> Result<(u16, serde_json::Value)> {
+    let url = request.url().to_owned();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let mut prefix = String::new();
+    let mut namespace = None;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value, true)?;
+        match name {
+            "prefix" => prefix = value,
+            "namespace" => namespace = Some(value),
+            _ => {
+                return Err(Error::Message(format!(
+                    "unknown query parameter {name}"
+                )))
+            }
+        }
+    }
+
+    let method = request.method().clone();
+    let not_allowed =
+        || Ok((405, json!({ "error": format!("{method} is not allowed") })));
+    if let Some(key) = path.strip_prefix("/keys/") {
+        let key = percent_decode(key, false)?;
+        if !matches!(method, Method::Get | Method::Put | Method::Delete) {
+            return not_allowed();
+        }
+        let store = keyspace(store, namespace)?;
+        return match method {
+            Method::Get => match store.get(key.clone())? {
+                Some(value) => Ok((200, json!({ "key": key, "value": value }))),
+                None => Err(Error::NonexistentKey),
+            },
+            // the body is the value as it is
+            Method::Put => {
+                let too_large = || {
+                    let error =
+                        format!("the body is over {MAX_BODY_LEN} bytes");
+                    Ok((413, json!({ "error": error })))
+                };
+                let length = request.body_length().map(|len| len as u64);
+                if length.is_some_and(|len| len > MAX_BODY_LEN) {
+                    return too_large();
+                }
+                // a chunked body has no length up front
+                let mut value = Vec::new();
+                request
+                    .as_reader()
+                    .take(MAX_BODY_LEN + 1)
+                    .read_to_end(&mut value)?;
+                if value.len() as u64 > MAX_BODY_LEN {
+                    return too_large();
+                }
+                store.set(key, String::from_utf8(value)?)?;
+                Ok((204, serde_json::Value::Null))
+            }
+            Method::Delete => {
+                store.remove(key)?;
+                Ok((204, serde_json::Value::Null))
+            }
+            _ => unreachable!("other methods are not allowed above"),
+        };
+    }
+    match (path, method.clone()) {
+        ("/keys", Method::Get) => {
+            let pairs = keyspace(store, namespace)?
+                .scan_prefix(prefix)?
+                .map(|kv| kv.map(|(key, value)| (key, value.into())))
+                .collect::<Result<serde_json::Map<_, _>>>()?;
+            Ok((200, pairs.into()))
+        }
+        ("/healthz", Method::Get) => Ok((200, json!({ "status": "ok" }))),
+        ("/stats", Method::Get) => Ok((
+            200,
+            json!({
+                "engine": stats.engine,
+                "uptime_secs": stats.started.elapsed().as_secs(),
+                "http_requests": stats.requests.load(Ordering::Relaxed),
+                "http_errors": stats.errors.load(Ordering::Relaxed),
+                "store": store.stats()?,
+            }),
+        )),
+        ("/keys" | "/healthz" | "/stats", _) => not_allowed(),
+        _ => Ok((404, json!({ "error": format!("no route for {path}") }))),
+    }
+}
+
+// decode `%XX` escapes of a URL component, and `+` as a space in queries
+fn percent_decode(s: &str, query: bool) -> Result<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'%' => {
+                let hex = rest
+                    .get(..2)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| {
+                        Error::Message(format!("invalid escape in {s:?}"))
+                    })?;
+                bytes.push(hex);
+                rest = &rest[2..];
+            }
+            b'+' if query => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
@@ -0,0 +1,249 @@
+use crate::{
+    auth::{Access, Credentials, User},
+    common::resp::{self, Value},
+    tls::Stream,
+    Error, KvsEngine, Result,
+};
+use slog::debug;
+use std::{
+    collections::BTreeMap,
+    io::{BufReader, BufWriter, Write},
+    ops::Bound,
+};
+
+// serve a Redis client until it quits
+pub(super) fn process_resp<E: KvsEngine>(
+    store: E,
+    logger: &slog::Logger,
+    credentials: Option<&Credentials>,
+    stream: Stream,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.clone());
+    let mut writer = BufWriter::new(stream);
+    let mut cursors = Cursors::default();
+    let mut user = None;
+
+    loop {
+        let args = match resp::read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            // the rest of the stream cannot be parsed
+            Err(e @ Error::Message(_)) => {
+                resp::write_value(&mut writer, &Value::error(&e))?;
+                writer.flush()?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        debug!(logger, "receive RESP command {:?}", args);
+        let quit = args[0].eq_ignore_ascii_case("quit");
+        let reply = if quit {
+            Value::Simple("OK".to_owned())
+        } else if args[0].eq_ignore_ascii_case("auth") {
+            auth_resp(credentials, &mut user, &args[1..])
+        } else if credentials.is_some() && user.is_none() {
+            Value::Error("NOAUTH Authentication required.".to_owned())
+        } else {
+            serve_resp(&store, user, &mut cursors, args)
+        };
+        resp::write_value(&mut writer, &reply)?;
+        // flush only after replying to all commands pipelined so far
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+// `AUTH [username] password`, as user `default` if the name is absent
+fn auth_resp<'a>(
+    credentials: Option<&'a Credentials>,
+    user: &mut Option<&'a User>,
+    args: &[String],
+) -> Value {
+    let (name, password) = match args {
+        [password] => ("default", password),
+        [name, password] => (name.as_str(), password),
+        _ => {
+            return Value::error("wrong number of arguments for 'auth' command")
+        }
+    };
+    let Some(credentials) = credentials else {
+        return Value::error("AUTH called without any password configured");
+    };
+    match credentials.authenticate(name, password) {
+        Ok(authenticated) => {
+            *user = Some(authenticated);
+            Value::Simple("OK".to_owned())
+        }
+        Err(_) => {
+            Value::Error("WRONGPASS invalid username-password pair".to_owned())
+        }
+    }
+}
+
+// cursors kept by a connection, since clients may stop scanning any time
+const MAX_CURSORS: usize = 1024;
+
+// keys after which `SCAN` cursors of a connection resume, by cursor
+#[derive(Default)]
+struct Cursors {
+    last: u64,
+    keys: BTreeMap<u64, String>,
+}
+
+impl Cursors {
+    // a new cursor resuming after `key`, dropping the oldest one if there
+    // are too many
+    fn insert(&mut self, key: String) -> u64 {
+        if self.keys.len() >= MAX_CURSORS {
+            self.keys.pop_first();
+        }
+        self.last += 1;
+        self.keys.insert(self.last, key);
+        self.last
+    }
+}
+
+// do a Redis command as `user`, or anyone if absent, failing with an error
+// reply
+fn serve_resp<E: KvsEngine>(
+    store: &E,
+    user: Option<&User>,
+    cursors: &mut Cursors,
+    mut args: Vec<String>,
+) -> Value {
+    let name = args.remove(0).to_ascii_lowercase();
+    let arity_ok = match name.as_str() {
+        "ping" => args.len() <= 1,
+        "get" | "incr" => args.len() == 1,
+        "set" => args.len() >= 2,
+        "del" | "exists" | "mget" | "scan" => !args.is_empty(),
+        _ => return Value::error(format!("unknown command '{name}'")),
+    };
+    if !arity_ok {
+        return Value::error(format!(
+            "wrong number of arguments for '{name}' command"
+        ));
+    }
+    if let Some(user) = user {
+        let (access, keys) = match name.as_str() {
+            "get" | "exists" | "mget" => (Access::Read, &args[..]),
+            "set" | "incr" => (Access::Write, &args[..1]),
+            "del" => (Access::Admin, &args[..]),
+            _ => (Access::Read, &[][..]),
+        };
+        let checked = keys
+            .iter()
+            .try_for_each(|key| user.check(access, None, key));
+        if let Err(e) = checked {
+            return Value::Error(format!("NOPERM {e}"));
+        }
+    }
+
+    let reply = match name.as_str() {
+        "ping" => Ok(match args.pop() {
+            Some(message) => Value::Bulk(Some(message)),
+            None => Value::Simple("PONG".to_owned()),
+        }),
+        "get" => store.get(args.remove(0)).map(Value::Bulk),
+        "set" if args.len() > 2 => return Value::error("syntax error"),
+        "set" => {
+            let value = args.pop().unwrap();
+            store
+                .set(args.pop().unwrap(), value)
+                .map(|()| Value::Simple("OK".to_owned()))
+        }
+        "del" => args
+            .into_iter()
+            .try_fold(0, |count, key| match store.remove(key) {
+                Ok(()) => Ok(count + 1),
+                Err(Error::NonexistentKey) => Ok(count),
+                Err(e) => Err(e),
+            })
+            .map(Value::Integer),
+        "exists" => args
+            .into_iter()
+            .try_fold(0, |count, key| {
+                Ok(count + store.get(key)?.is_some() as i64)
+            })
+            .map(Value::Integer),
+        "mget" => store.get_many(args).map(|values| {
+            Value::Array(values.into_iter().map(Value::Bulk).collect())
+        }),
+        "incr" => match store.increment(args.remove(0), 1) {
+            Err(Error::ParseInt(_) | Error::Message(_)) => {
+                return Value::error("value is not an integer or out of range")
+            }
+            res => res.map(Value::Integer),
+        },
+        "scan" => return scan(store, user, cursors, &args),
+        _ => unreachable!("unknown commands are rejected above"),
+    };
+    reply.unwrap_or_else(Value::error)
+}
+
+// `SCAN cursor [MATCH pattern] [COUNT count]`, examining `count` keys, and
+// skipping those `user` may not read
+fn scan<E: KvsEngine>(
+    store: &E,
+    user: Option<&User>,
+    cursors: &mut Cursors,
+    args: &[String],
+) -> Value {
+    let start = match args[0].parse::<u64>() {
+        Ok(0) => Bound::Unbounded,
+        Ok(cursor) => match cursors.keys.remove(&cursor) {
+            Some(key) => Bound::Excluded(key),
+            None => return Value::error("invalid cursor"),
+        },
+        Err(_) => return Value::error("invalid cursor"),
+    };
+    let mut pattern = None;
+    let mut count = 10;
+    for option in args[1..].chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case("match") => {
+                pattern = Some(value.as_str());
+            }
+            [name, value] if name.eq_ignore_ascii_case("count") => {
+                match value.parse::<usize>() {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return Value::error("syntax error"),
+                }
+            }
+            _ => return Value::error("syntax error"),
+        }
+    }
+
+    let mut pairs = match store.scan((start, Bound::Unbounded)) {
+        Ok(scan) => scan.peekable(),
+        Err(e) => return Value::error(e),
+    };
+    let mut keys = Vec::new();
+    let mut last = None;
+    for pair in pairs.by_ref().take(count) {
+        let key = match pair {
+            Ok((key, _)) => key,
+            Err(e) => return Value::error(e),
+        };
+        let readable =
+            user.is_none_or(|user| user.allows(Access::Read, None, &key));
+        if readable
+            && pattern.is_none_or(|pattern| resp::matches(pattern, &key))
+        {
+            keys.push(Value::Bulk(Some(key.clone())));
+        }
+        last = Some(key);
+    }
+    let cursor = match (pairs.peek(), last) {
+        (Some(_), Some(key)) => cursors.insert(key),
+        _ => 0,
+    };
+    Value::Array(vec![
+        Value::Bulk(Some(cursor.to_string())),
+        Value::Array(keys),
+    ])
+}
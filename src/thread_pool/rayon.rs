@@ -12,6 +12,8 @@ impl ThreadPool for RayonThreadPool {
                 std::thread::spawn(|| thread.run());
                 Ok(())
             })
+            // a panicking job aborts the process without a handler
+            .panic_handler(|_| {})
             .build()
             .expect("unable to crete thread pool using `rayon`");
         Ok(RayonThreadPool { pool })
@@ -21,8 +23,6 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.install(|| {
-            job();
-        });
+        self.pool.spawn(job);
     }
 }
@@ -0,0 +1,238 @@
+use crate::{Error, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig,
+    ServerConnection, StreamOwned,
+};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Configuration of a server with the certificate chain in the PEM file
+/// `cert` and its private key in `key`.
+///
+/// Clients must present certificates signed by CAs in `client_ca` if it is
+/// given.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+    let builder = match client_ca {
+        Some(path) => {
+            let roots = Arc::new(load_roots(path)?);
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(roots, provider())
+                    .build()
+                    .map_err(|e| {
+                        Error::Message(format!("invalid client CA: {e}"))
+                    })?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(Arc::new(config))
+}
+
+/// Configuration of a client trusting servers signed by CAs in the PEM file
+/// `ca`.
+///
+/// `identity` is the certificate chain and the private key of the client,
+/// for servers requiring one.
+pub fn client_config(
+    ca: &Path,
+    identity: Option<(&Path, &Path)>,
+) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => {
+            builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs =
+        rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(Error::Message(format!(
+            "no certificate in {}",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        Error::Message(format!("no private key in {}", path.display()))
+    })
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Connection to a peer in plaintext or over TLS.
+///
+/// Clones share the connection, so its reading half and writing half can be
+/// buffered separately, but they must be used by one thread at a time.
+#[derive(Clone)]
+pub struct Stream(Arc<Mutex<Inner>>);
+
+enum Inner {
+    Plain(TcpStream),
+    Client(Box<StreamOwned<ClientConnection, TcpStream>>),
+    Server(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Use `stream` in plaintext.
+    pub fn plain(stream: TcpStream) -> Stream {
+        Stream(Arc::new(Mutex::new(Inner::Plain(stream))))
+    }
+
+    /// Do the TLS handshake over `stream` as a server.
+    pub fn accept(
+        mut stream: TcpStream,
+        config: Arc<ServerConfig>,
+    ) -> Result<Stream> {
+        let mut conn = ServerConnection::new(config)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        let inner = Inner::Server(Box::new(StreamOwned::new(conn, stream)));
+        Ok(Stream(Arc::new(Mutex::new(inner))))
+    }
+
+    /// Do the TLS handshake over `stream` as a client of the server named
+    /// `server_name`, a DNS name or an IP address in its certificate.
+    pub fn connect(
+        mut stream: TcpStream,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> Result<Stream> {
+        let name =
+            ServerName::try_from(server_name.to_owned()).map_err(|_| {
+                Error::Message(format!("invalid server name {server_name:?}"))
+            })?;
+        let mut conn = ClientConnection::new(config, name)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        let inner = Inner::Client(Box::new(StreamOwned::new(conn, stream)));
+        Ok(Stream(Arc::new(Mutex::new(inner))))
+    }
+
+    /// Whether the peer has closed the connection or it is broken, found
+    /// without blocking.
+    ///
+    /// Data sent by the peer is read and discarded.
+    pub fn is_closed(&self) -> io::Result<bool> {
+        let mut inner = self.0.lock().unwrap();
+        inner.sock().set_nonblocking(true)?;
+        let mut buf = [0; 512];
+        let closed = loop {
+            let read = match &mut *inner {
+                Inner::Plain(stream) => stream.read(&mut buf),
+                Inner::Client(stream) => stream.read(&mut buf),
+                Inner::Server(stream) => stream.read(&mut buf),
+            };
+            match read {
+                Ok(0) => break true,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break true,
+            }
+        };
+        inner.sock().set_nonblocking(false)?;
+        Ok(closed)
+    }
+}
+
+impl Inner {
+    fn sock(&self) -> &TcpStream {
+        match self {
+            Inner::Plain(stream) => stream,
+            Inner::Client(stream) => &stream.sock,
+            Inner::Server(stream) => &stream.sock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut *self.0.lock().unwrap() {
+            Inner::Plain(stream) => stream.read(buf),
+            Inner::Client(stream) => stream.read(buf),
+            Inner::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut *self.0.lock().unwrap() {
+            Inner::Plain(stream) => stream.write(buf),
+            Inner::Client(stream) => stream.write(buf),
+            Inner::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut *self.0.lock().unwrap() {
+            Inner::Plain(stream) => stream.flush(),
+            Inner::Client(stream) => stream.flush(),
+            Inner::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+// tell the peer the connection ends on purpose, or it sees a truncation
+impl Drop for Inner {
+    fn drop(&mut self) {
+        match self {
+            Inner::Plain(_) => {}
+            Inner::Client(stream) => {
+                stream.conn.send_close_notify();
+                while stream.conn.wants_write() {
+                    if stream.conn.write_tls(&mut stream.sock).is_err() {
+                        break;
+                    }
+                }
+            }
+            Inner::Server(stream) => {
+                stream.conn.send_close_notify();
+                while stream.conn.wants_write() {
+                    if stream.conn.write_tls(&mut stream.sock).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
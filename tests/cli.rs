@@ -1,7 +1,20 @@
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::{
+    client::{ClientOptions, KvsClient, Protocol},
+    common::{binary, ErrorCode, Request, Response, Tagged, PROTOCOL_VERSION},
+    server::KvsServer,
+    thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool},
+    KvStore, KvsEngine,
+};
 use predicates::str::{contains, is_empty};
+use serde_json::{json, Deserializer};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -213,6 +226,1073 @@ fn cli_wrong_engine() {
     }
 }
 
+// `kvs-server --migrate` should move data persisted with another engine,
+// encrypted with the key given.
+#[test]
+fn cli_migrate_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("key"), "1".repeat(64)).unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4006", "--migrate"])
+        .args(["--key-file", "key"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+
+    let identity = temp_dir.path().join(".kv_data").join("identity");
+    assert_eq!(fs::read_to_string(&identity).unwrap(), "kvs");
+    let data = fs::read(temp_dir.path().join(".kv_data").join("data-1"));
+    assert!(!data.unwrap().windows(6).any(|w| w == b"value1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--key-file", "key", "migrate"])
+        .args(["--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 keys migrated"));
+    assert_eq!(fs::read_to_string(&identity).unwrap(), "sled");
+}
+
+// `kvs-client watch <PREFIX>` should print changes of keys under the prefix.
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "app/", "--addr", "127.0.0.1:4007"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for args in [
+        ["set", "app/key", "value"].as_slice(),
+        &["set", "key", "value"],
+        &["rm", "app/key"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4007"])
+            .assert()
+            .success();
+    }
+
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    let first = lines.next().unwrap().unwrap();
+    let second = lines.next().unwrap().unwrap();
+    assert!(first.ends_with(" set app/key value"), "{first}");
+    assert!(second.ends_with(" rm app/key"), "{second}");
+
+    watcher.kill().expect("watcher exited before killed");
+    watcher.wait().expect("unable to wait for watcher");
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// `--namespace` should keep keys apart from the default keyspace.
+#[test]
+fn cli_namespace() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4008"])
+            .assert()
+            .success()
+    };
+    client(&["set", "key1", "value1", "--namespace", "users"]);
+    client(&["set", "key1", "value2"]);
+    client(&["get", "key1", "--namespace", "users"]).stdout("value1\n");
+    client(&["get", "key1"]).stdout("value2\n");
+    client(&["get", "key1", "--namespace", "orders"]).stdout("Key not found\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// `kvs-client incr` should print the sum, and fail on a non-integer value.
+#[test]
+fn cli_incr() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(args).args(["--addr", "127.0.0.1:4009"]);
+        client
+    };
+    client(&["incr", "counter"])
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["incr", "counter", "-3"])
+        .assert()
+        .success()
+        .stdout("-2\n");
+    client(&["set", "key1", "value1"]).assert().success();
+    client(&["incr", "key1"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid digit"));
+    client(&["get", "key1"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// `kvs-client tx` should print values got, and write nothing if a check
+// fails.
+#[test]
+fn cli_transaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut client = Command::cargo_bin("kvs-client").unwrap();
+        client.args(args).args(["--addr", "127.0.0.1:4010"]);
+        client
+    };
+    client(&[
+        "tx", "absent", "key1", "set", "key1", "value1", "get", "key1",
+    ])
+    .assert()
+    .success()
+    .stdout("value1\n");
+    client(&["tx", "set", "key2", "value2", "check", "key1", "value2"])
+        .assert()
+        .failure()
+        .stderr(contains("check failed on key key1"));
+    client(&["tx", "get", "key1", "get", "key2"])
+        .assert()
+        .success()
+        .stdout("value1\nKey not found\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// Misses and failures should be told apart from values by the server.
+#[test]
+fn server_typed_responses() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect("127.0.0.1:4011").unwrap();
+    let mut responses = Deserializer::from_reader(stream.try_clone().unwrap())
+        .into_iter::<Response>();
+    let mut send = |request: Request| {
+        serde_json::to_writer(&stream, &request).unwrap();
+        responses.next().unwrap().unwrap()
+    };
+    let get = || Request::Get {
+        key: "key1".to_owned(),
+        namespace: None,
+    };
+    assert!(matches!(send(get()), Response::Value(None)));
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: "Key not found".to_owned(),
+        namespace: None,
+    };
+    assert!(matches!(send(set), Response::Ok));
+    assert!(
+        matches!(send(get()), Response::Value(Some(v)) if v == "Key not found")
+    );
+    let remove = Request::Remove {
+        key: "key2".to_owned(),
+        namespace: None,
+    };
+    assert!(matches!(
+        send(remove),
+        Response::Error {
+            code: ErrorCode::NotFound,
+            ..
+        }
+    ));
+    let incr = Request::Incr {
+        key: "key1".to_owned(),
+        delta: 1,
+        namespace: None,
+    };
+    assert!(matches!(
+        send(incr),
+        Response::Error {
+            code: ErrorCode::Invalid,
+            ..
+        }
+    ));
+    // the connection is still usable
+    assert!(matches!(send(get()), Response::Value(Some(_))));
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// Clients opening with the binary magic should talk in frames, while others
+// keep using JSON on the same server.
+#[test]
+fn server_hello() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4013", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect("127.0.0.1:4013").unwrap();
+    let mut responses = Deserializer::from_reader(stream.try_clone().unwrap())
+        .into_iter::<Response>();
+    let hello = |version| Request::Hello {
+        version,
+        features: vec!["encoding:json".to_owned()],
+    };
+    serde_json::to_writer(&stream, &hello(PROTOCOL_VERSION)).unwrap();
+    match responses.next().unwrap().unwrap() {
+        Response::Hello { version, features } => {
+            assert_eq!(version, PROTOCOL_VERSION);
+            assert!(features.contains(&"engine:sled".to_owned()));
+            assert!(features.contains(&"encoding:binary".to_owned()));
+        }
+        response => panic!("unexpected response {response:?}"),
+    }
+
+    // an incompatible client is rejected and disconnected
+    serde_json::to_writer(&stream, &hello(PROTOCOL_VERSION + 1)).unwrap();
+    assert!(matches!(
+        responses.next().unwrap().unwrap(),
+        Response::Error {
+            code: ErrorCode::Unsupported,
+            ..
+        }
+    ));
+    assert!(responses.next().is_none());
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn server_binary_frames() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4012").unwrap();
+    stream.write_all(&binary::MAGIC).unwrap();
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic).unwrap();
+    assert_eq!(magic, binary::MAGIC);
+
+    // `Set` of key "k" to "v" without a namespace, with ID 7
+    let frame = [
+        20, 0, 0, 0, 0x01, 7, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, b'k', 1, 0, 0,
+        0, b'v', 0,
+    ];
+    stream.write_all(&frame).unwrap();
+    let mut reply = [0; 13];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [9, 0, 0, 0, 0x81, 7, 0, 0, 0, 0, 0, 0, 0]);
+
+    let value = "line\n\"quoted\"\0".repeat(1 << 16);
+    let set = Request::Set {
+        key: "key1".to_owned(),
+        value: value.clone(),
+        namespace: None,
+    };
+    binary::write_request(&mut stream, 1, &set).unwrap();
+    assert!(matches!(
+        binary::read_response(&mut stream).unwrap(),
+        Some(Tagged {
+            id: 1,
+            body: Response::Ok
+        })
+    ));
+    let get = Request::MGet {
+        keys: vec!["key1".to_owned(), "k".to_owned(), "key2".to_owned()],
+        namespace: None,
+    };
+    binary::write_request(&mut stream, 2, &get).unwrap();
+    match binary::read_response(&mut stream).unwrap().map(|r| r.body) {
+        Some(Response::Values(values)) => {
+            assert_eq!(values, [Some(value), Some("v".to_owned()), None])
+        }
+        response => panic!("unexpected response {response:?}"),
+    }
+    // lengths are bounded before reading the rest of a frame
+    let mut huge = [0xff, 0xff, 0xff, 0x7f].as_slice().chain(io::repeat(0));
+    assert!(binary::read_request(&mut huge).is_err());
+
+    for protocol in ["binary", "json"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "k", "--addr", "127.0.0.1:4012"])
+            .args(["--protocol", protocol])
+            .assert()
+            .success()
+            .stdout("v\n");
+    }
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn client_pipeline() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for protocol in [Protocol::Binary, Protocol::Json] {
+        let mut client = KvsClient::connect_with(
+            "127.0.0.1:4014",
+            ClientOptions::new().protocol(protocol),
+        )
+        .unwrap();
+        assert!(client.features().contains(&"engine:kvs".to_owned()));
+
+        let sets: Vec<_> = (0..100)
+            .map(|i| Request::Set {
+                key: format!("key{i}"),
+                value: format!("{protocol:?}{i}"),
+                namespace: None,
+            })
+            .collect();
+        let responses = client.pipeline(&sets, 8).unwrap();
+        assert_eq!(responses.len(), 100);
+        assert!(responses.iter().all(|r| matches!(r, Response::Ok)));
+
+        // responses stay in the order of requests, errors included
+        let incrs: Vec<_> = (0..101)
+            .map(|i| Request::Incr {
+                key: format!("key{}", 100 - i),
+                delta: 1,
+                namespace: None,
+            })
+            .collect();
+        let responses = client.pipeline(&incrs, 16).unwrap();
+        assert!(matches!(responses[0], Response::Integer(1)));
+        assert!(responses[1..].iter().all(|r| matches!(
+            r,
+            Response::Error {
+                code: ErrorCode::Invalid,
+                ..
+            }
+        )));
+
+        let get = Request::Get {
+            key: "key100".to_owned(),
+            namespace: None,
+        };
+        assert!(matches!(
+            client.request(&get).unwrap(),
+            Response::Value(Some(v)) if v == "1"
+        ));
+        client
+            .request(&Request::Remove {
+                key: "key100".to_owned(),
+                namespace: None,
+            })
+            .unwrap();
+    }
+
+    // large requests and responses overflow socket buffers when all of them
+    // are sent before reading any response
+    let key = "k".repeat(10_000);
+    let mut client = KvsClient::connect("127.0.0.1:4014").unwrap();
+    let set = Request::Set {
+        key: key.clone(),
+        value: "v".repeat(10_000),
+        namespace: None,
+    };
+    assert!(matches!(client.request(&set).unwrap(), Response::Ok));
+    let gets: Vec<_> = (0..2000)
+        .map(|_| Request::Get {
+            key: key.clone(),
+            namespace: None,
+        })
+        .collect();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        sender
+            .send(client.pipeline(&gets, 2000).unwrap().len())
+            .unwrap()
+    });
+    let answered = receiver
+        .recv_timeout(Duration::from_secs(30))
+        .expect("pipeline is stuck");
+    assert_eq!(answered, 2000);
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn server_resp() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4015", "--resp-addr", "127.0.0.1:4016"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4016").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut command = |args: &[&str]| {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request += &format!("${}\r\n{arg}\r\n", arg.len());
+        }
+        stream.write_all(request.as_bytes()).unwrap();
+        read_reply(&mut reader)
+    };
+
+    assert_eq!(command(&["PING"]), "+PONG\r\n");
+    assert_eq!(command(&["ping", "hi"]), "$2\r\nhi\r\n");
+    assert_eq!(command(&["SET", "k1", "v 1"]), "+OK\r\n");
+    assert_eq!(command(&["GET", "k1"]), "$3\r\nv 1\r\n");
+    assert_eq!(command(&["GET", "k2"]), "$-1\r\n");
+    assert_eq!(command(&["MGET", "k1", "k2"]), "*2\r\n$3\r\nv 1\r\n$-1\r\n");
+    assert_eq!(command(&["INCR", "n"]), ":1\r\n");
+    assert_eq!(
+        command(&["INCR", "k1"]),
+        "-ERR value is not an integer or out of range\r\n"
+    );
+    assert_eq!(command(&["EXISTS", "k1", "k2", "n"]), ":2\r\n");
+    assert_eq!(command(&["DEL", "k1", "k2"]), ":1\r\n");
+    assert_eq!(command(&["GET", "k1"]), "$-1\r\n");
+    assert_eq!(
+        command(&["GET"]),
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        command(&["FLUSHALL"]),
+        "-ERR unknown command 'flushall'\r\n"
+    );
+
+    for i in 0..10 {
+        command(&["SET", &format!("a{i}"), "v"]);
+        command(&["SET", &format!("b{i}"), "v"]);
+    }
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    loop {
+        let reply = command(&["SCAN", &cursor, "MATCH", "a*", "COUNT", "3"]);
+        // `*2`, the cursor, then `*n` and keys with their lengths
+        let parts: Vec<_> = reply.split("\r\n").collect();
+        cursor = parts[2].to_owned();
+        keys.extend(parts[5..].iter().step_by(2).map(|k| k.to_string()));
+        if cursor == "0" {
+            break;
+        }
+    }
+    let expected: Vec<_> = (0..10).map(|i| format!("a{i}")).collect();
+    assert_eq!(keys, expected);
+
+    // cursors of abandoned scans are dropped, oldest first
+    let cursors: Vec<_> = (0..1025)
+        .map(|_| {
+            let reply = command(&["SCAN", "0", "COUNT", "1"]);
+            reply.split("\r\n").nth(2).unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(command(&["SCAN", &cursors[0]]), "-ERR invalid cursor\r\n");
+    assert!(command(&["SCAN", &cursors[1]]).starts_with("*2\r\n"));
+
+    // inline and pipelined commands
+    stream.write_all(b"PING\r\nGET a0\r\nQUIT\r\n").unwrap();
+    assert_eq!(read_reply(&mut reader), "+PONG\r\n");
+    assert_eq!(read_reply(&mut reader), "$1\r\nv\r\n");
+    assert_eq!(read_reply(&mut reader), "+OK\r\n");
+    assert_eq!(reader.read_line(&mut String::new()).unwrap(), 0);
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// read a whole RESP reply as it is sent
+fn read_reply(reader: &mut impl BufRead) -> String {
+    let mut reply = String::new();
+    reader.read_line(&mut reply).unwrap();
+    let len = reply[1..reply.len() - 2].parse::<i64>();
+    match (reply.as_bytes()[0], len) {
+        (b'$', Ok(len)) if len >= 0 => {
+            let mut bulk = vec![0; len as usize + 2];
+            reader.read_exact(&mut bulk).unwrap();
+            reply += &String::from_utf8(bulk).unwrap();
+        }
+        (b'*', Ok(len)) => {
+            for _ in 0..len {
+                reply += &read_reply(reader);
+            }
+        }
+        _ => {}
+    }
+    reply
+}
+
+// Patterns of `SCAN MATCH` should match like Redis, and many `*` should not
+// take exponential time on a key they miss.
+#[test]
+fn resp_glob() {
+    use kvs::common::resp::matches;
+
+    assert!(matches("*", ""));
+    assert!(matches("a*", "abc"));
+    assert!(!matches("a*", "bac"));
+    assert!(matches("*c", "abc"));
+    assert!(matches("a*b*c", "aXbYbZc"));
+    assert!(!matches("a*b*c", "aXbYbZ"));
+    assert!(matches("a?c", "abc"));
+    assert!(!matches("a?c", "ac"));
+    assert!(matches("[a-c]x", "bx"));
+    assert!(!matches("[^a-c]x", "bx"));
+    assert!(matches("[\\]]x", "]x"));
+    assert!(matches("a\\*", "a*"));
+    assert!(!matches("a\\*", "ab"));
+    assert!(matches("a**", "a"));
+
+    let key = "a".repeat(60);
+    let pattern = format!("{}b", "*a".repeat(30));
+    let start = std::time::Instant::now();
+    assert!(!matches(&pattern, &key));
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn server_http() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4017", "--http-addr", "127.0.0.1:4018"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let http = |method: &str, target: &str, body: &str| {
+        let mut stream = TcpStream::connect("127.0.0.1:4018").unwrap();
+        write!(
+            stream,
+            "{method} {target} HTTP/1.1\r\nHost: localhost\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse::<u16>().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let body = match body {
+            "" => serde_json::Value::Null,
+            body => serde_json::from_str(body).unwrap(),
+        };
+        (status, body)
+    };
+
+    assert_eq!(http("GET", "/healthz", ""), (200, json!({"status": "ok"})));
+    assert_eq!(http("PUT", "/keys/a%2Fb", "x y").0, 204);
+    assert_eq!(http("PUT", "/keys/a%2Fc", "z").0, 204);
+    assert_eq!(http("PUT", "/keys/b?namespace=ns", "w").0, 204);
+    assert_eq!(
+        http("GET", "/keys/a%2Fb", ""),
+        (200, json!({"key": "a/b", "value": "x y"}))
+    );
+    assert_eq!(
+        http("GET", "/keys?prefix=a%2F", ""),
+        (200, json!({"a/b": "x y", "a/c": "z"}))
+    );
+    assert_eq!(
+        http("GET", "/keys?namespace=ns", ""),
+        (200, json!({"b": "w"}))
+    );
+    assert_eq!(http("DELETE", "/keys/a%2Fb", "").0, 204);
+    assert_eq!(http("DELETE", "/keys/a%2Fb", "").0, 404);
+    assert_eq!(http("GET", "/keys/a%2Fb", "").0, 404);
+    assert_eq!(http("GET", "/keys/a?namespace=", "").0, 400);
+    assert_eq!(http("POST", "/keys/a", "").0, 405);
+    assert_eq!(http("GET", "/nowhere", "").0, 404);
+
+    // answered before the body is sent
+    let mut stream = TcpStream::connect("127.0.0.1:4018").unwrap();
+    write!(
+        stream,
+        "PUT /keys/big HTTP/1.1\r\nHost: localhost\r\n\
+        Content-Length: {}\r\n\r\n",
+        (512 << 20) + 1
+    )
+    .unwrap();
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status).unwrap();
+    assert_eq!(&status[9..12], "413");
+
+    let (status, stats) = http("GET", "/stats", "");
+    assert_eq!(status, 200);
+    assert_eq!(stats["engine"], "kvs");
+    assert_eq!(stats["http_requests"], 14);
+    assert_eq!(stats["http_errors"], 6);
+    assert!(stats["store"]["last_seq"].as_u64().unwrap() >= 4);
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn server_tls() {
+    let temp_dir = TempDir::new().unwrap();
+    let certs = temp_dir.path().join("certs");
+    write_certs(&certs);
+    let mutual_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4019", "--tls-cert"])
+        .arg(certs.join("server.pem"))
+        .arg("--tls-key")
+        .arg(certs.join("server.key"))
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let mut mutual_server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4020", "--tls-cert"])
+        .arg(certs.join("server.pem"))
+        .arg("--tls-key")
+        .arg(certs.join("server.key"))
+        .arg("--tls-client-ca")
+        .arg(certs.join("ca.pem"))
+        .current_dir(&mutual_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |addr: &str, args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&certs);
+        command
+    };
+    client("127.0.0.1:4019", &["set", "key", "value", "--ca", "ca.pem"])
+        .assert()
+        .success();
+    client("127.0.0.1:4019", &["get", "key", "--ca", "ca.pem"])
+        .assert()
+        .success()
+        .stdout("value\n");
+    client("127.0.0.1:4019", &["get", "key", "--protocol", "json"])
+        .assert()
+        .failure();
+
+    let config = kvs::tls::client_config(&certs.join("ca.pem"), None).unwrap();
+    let mut tls_client = KvsClient::connect_with(
+        "127.0.0.1:4019",
+        ClientOptions::new()
+            .protocol(Protocol::Json)
+            .tls(config, "localhost"),
+    )
+    .unwrap();
+    assert!(tls_client.features().contains(&"tls".to_owned()));
+    assert!(matches!(
+        tls_client
+            .request(&Request::Get {
+                key: "key".to_owned(),
+                namespace: None,
+            })
+            .unwrap(),
+        Response::Value(Some(v)) if v == "value"
+    ));
+
+    // the other server only serves clients with certificates of its CA
+    client("127.0.0.1:4020", &["set", "key", "value", "--ca", "ca.pem"])
+        .assert()
+        .failure();
+    let identity = ["--cert", "client.pem", "--key", "client.key"];
+    client("127.0.0.1:4020", &["set", "key", "value", "--ca", "ca.pem"])
+        .args(identity)
+        .assert()
+        .success();
+    client("127.0.0.1:4020", &["get", "key", "--ca", "ca.pem"])
+        .args(identity)
+        .assert()
+        .success()
+        .stdout("value\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+    mutual_server.kill().expect("server exited before killed");
+    mutual_server.wait().expect("unable to wait for server");
+}
+
+// write a CA, and a server certificate and a client one signed by it to `dir`
+fn write_certs(dir: &Path) {
+    use rcgen::{
+        BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+
+    fs::create_dir_all(dir).unwrap();
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+    for (name, names, usage) in [
+        (
+            "server",
+            vec!["localhost".to_owned(), "127.0.0.1".to_owned()],
+            ExtendedKeyUsagePurpose::ServerAuth,
+        ),
+        ("client", Vec::new(), ExtendedKeyUsagePurpose::ClientAuth),
+    ] {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(names).unwrap();
+        params.extended_key_usages = vec![usage];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        fs::write(dir.join(format!("{name}.pem")), cert.pem()).unwrap();
+        fs::write(dir.join(format!("{name}.key")), key.serialize_pem())
+            .unwrap();
+    }
+}
+
+#[test]
+fn server_auth() {
+    let temp_dir = TempDir::new().unwrap();
+    let hash = |password: &str| {
+        let output = assert_cmd::Command::cargo_bin("kvs-server")
+            .unwrap()
+            .arg("--hash-password")
+            .write_stdin(format!("{password}\n"))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    };
+    let users = format!(
+        "# NAME PASSWORD-HASH RULE...\n\
+        root {} admin@*:\n\
+        alice {} read: write:app/\n\
+        \n\
+        bob {} read@logs:\n",
+        hash("root-pw"),
+        hash("alice-pw"),
+        hash("bob-pw"),
+    );
+    fs::write(temp_dir.path().join("users"), users).unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4021", "--auth-file", "users"])
+        .args(["--resp-addr", "127.0.0.1:4022"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", "127.0.0.1:4021"]);
+        command
+    };
+    let alice = ["--user", "alice", "--password", "alice-pw"];
+    let root = ["--user", "root", "--password", "root-pw"];
+    client(&["get", "app/a"])
+        .assert()
+        .failure()
+        .stderr(contains("authentication required"));
+    client(&["get", "app/a", "--user", "alice", "--password", "bob-pw"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid username or password"));
+    client(&["get", "app/a", "--user", "eve", "--password", "alice-pw"])
+        .assert()
+        .failure()
+        .stderr(contains("invalid username or password"));
+    client(&["set", "app/a", "1"])
+        .args(alice)
+        .assert()
+        .success();
+    client(&["get", "app/a"])
+        .args(alice)
+        .assert()
+        .success()
+        .stdout("1\n");
+    client(&["set", "b", "2"])
+        .args(alice)
+        .assert()
+        .failure()
+        .stderr(contains("no write access"));
+    client(&["tx", "get", "app/a", "rm", "app/a"])
+        .args(alice)
+        .assert()
+        .failure()
+        .stderr(contains("no admin access"));
+    client(&["set", "b", "2", "--namespace", "logs"])
+        .args(root)
+        .assert()
+        .success();
+    client(&["get", "b", "--namespace", "logs"])
+        .args(["--user", "bob", "--password", "bob-pw"])
+        .assert()
+        .success()
+        .stdout("2\n");
+    client(&["get", "app/a"])
+        .args(["--user", "bob", "--password", "bob-pw"])
+        .assert()
+        .failure()
+        .stderr(contains("no read access"));
+
+    let mut tagged = KvsClient::connect("127.0.0.1:4021").unwrap();
+    assert!(tagged.features().contains(&"auth".to_owned()));
+    let remove = Request::Remove {
+        key: "app/a".to_owned(),
+        namespace: None,
+    };
+    assert!(matches!(
+        tagged.request(&remove).unwrap(),
+        Response::Error {
+            code: ErrorCode::Denied,
+            ..
+        }
+    ));
+    let auth = Request::Auth {
+        user: "root".to_owned(),
+        password: "root-pw".to_owned(),
+    };
+    assert!(matches!(tagged.request(&auth).unwrap(), Response::Ok));
+    assert!(matches!(tagged.request(&remove).unwrap(), Response::Ok));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4022").unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut command = |args: &[&str]| {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request += &format!("${}\r\n{arg}\r\n", arg.len());
+        }
+        stream.write_all(request.as_bytes()).unwrap();
+        read_reply(&mut reader)
+    };
+    assert_eq!(
+        command(&["GET", "app/a"]),
+        "-NOAUTH Authentication required.\r\n"
+    );
+    assert!(command(&["AUTH", "alice", "root-pw"]).starts_with("-WRONGPASS"));
+    assert_eq!(command(&["AUTH", "alice", "alice-pw"]), "+OK\r\n");
+    assert_eq!(command(&["SET", "app/c", "3"]), "+OK\r\n");
+    assert!(command(&["SET", "c", "3"]).starts_with("-NOPERM"));
+    assert!(command(&["DEL", "app/c"]).starts_with("-NOPERM"));
+    assert_eq!(command(&["GET", "app/c"]), "$1\r\n3\r\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+
+    // passwords would be sent to the gateway in plaintext
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4021", "--auth-file", "users"])
+        .args(["--http-addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("HTTP gateway has no TLS"));
+}
+
+#[cfg(unix)]
+#[test]
+fn server_shutdown_on_signal() {
+    let temp_dir = TempDir::new().unwrap();
+    let start = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", "127.0.0.1:4024", "--drain-timeout", "30"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut server = start();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut idle = KvsClient::connect("127.0.0.1:4024").unwrap();
+    let set = Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        namespace: None,
+    };
+    assert!(matches!(idle.request(&set).unwrap(), Response::Ok));
+    // a watch ends on shutdown, long before the deadline
+    let mut watch = TcpStream::connect("127.0.0.1:4024").unwrap();
+    watch.write_all(br#"{"Watch":{"prefix":""}}"#).unwrap();
+    thread::sleep(Duration::from_millis(200));
+
+    Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .assert()
+        .success();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || sender.send(server.wait().unwrap()).unwrap());
+    let status = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server does not exit after shutdown");
+    assert!(status.success());
+    assert!(idle.request(&set).is_err());
+    assert_eq!(watch.read(&mut [0; 64]).unwrap(), 0);
+
+    let mut server = start();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", "127.0.0.1:4024"])
+        .assert()
+        .success()
+        .stdout("value\n");
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+#[test]
+fn server_shutdown_handle() {
+    let temp_dir = TempDir::new().unwrap();
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let pool = NaiveThreadPool::new(2).unwrap();
+    let server = KvsServer::new("kvs", store, pool, logger);
+    let shutdown = server.shutdown_handle();
+    let serving = thread::spawn(move || server.run("127.0.0.1:4025"));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect("127.0.0.1:4025").unwrap();
+    let set = Request::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+        namespace: None,
+    };
+    assert!(matches!(client.request(&set).unwrap(), Response::Ok));
+    assert!(!shutdown.is_shutdown());
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+    assert!(TcpStream::connect("127.0.0.1:4025").is_err());
+
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get("key".to_owned()).unwrap().as_deref(),
+        Some("value")
+    );
+}
+
+// A watch should end when its watcher is gone, freeing its pool thread.
+#[test]
+fn server_watcher_gone() {
+    let temp_dir = TempDir::new().unwrap();
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let server = KvsServer::new("kvs", store, pool, logger);
+    let shutdown = server.shutdown_handle();
+    let serving = thread::spawn(move || server.run("127.0.0.1:4028"));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut watch = TcpStream::connect("127.0.0.1:4028").unwrap();
+    watch.write_all(br#"{"Watch":{"prefix":""}}"#).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    drop(watch);
+
+    let get = Request::Get {
+        key: "key".to_owned(),
+        namespace: None,
+    };
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut client = KvsClient::connect("127.0.0.1:4028").unwrap();
+        sender.send(client.request(&get).unwrap()).unwrap();
+    });
+    let response = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the only pool thread is still watching");
+    assert!(matches!(response, Response::Value(None)));
+    shutdown.shutdown();
+    serving.join().unwrap().unwrap();
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -323,6 +1403,21 @@ fn cli_access_server(engine: &str, addr: &str) {
         .assert()
         .success()
         .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mset", "key3", "value3", "key4", "value4", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["mget", "key4", "key1", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value4\nKey not found\nvalue3\n");
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -336,3 +1431,259 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs dump` output should be accepted by `kvs load`.
+#[test]
+fn cli_dump_and_load() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key2", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout.clone()).unwrap(),
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+        {\"key\":\"key2\",\"value\":\"value2\"}\n"
+    );
+
+    let another_dir = TempDir::new().unwrap();
+    assert_cmd::Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["load"])
+        .current_dir(&another_dir)
+        .write_stdin(output.stdout)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&another_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+// `kvs verify` should exit with 2 on damaged data until `kvs repair` runs.
+#[test]
+fn cli_verify_and_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let path = temp_dir.path().join(".kv_data").join("data-1");
+    let mut data = fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&path, data).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(contains("data-1"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("quarantine"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+// `kvs inspect --key` should print the version history of a key.
+#[test]
+fn cli_inspect() {
+    let temp_dir = TempDir::new().unwrap();
+    for value in ["value1", "value2"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["set", "key1", value])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect", "--key", "key1"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("data-1 offset=16 "));
+    assert!(lines[0].ends_with("live=false"));
+    assert!(lines[1].starts_with("data-2 offset=16 "));
+    assert!(lines[1].ends_with("live=true"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect", "data-2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value_len=6 tombstone=false live=true"));
+
+    // a range tombstone removing the key is part of its history
+    let store = KvStore::open(temp_dir.path().join(".kv_data")).unwrap();
+    store
+        .remove_range("key0".to_owned().."key2".to_owned())
+        .unwrap();
+    store.remove_range("key2".to_owned()..).unwrap();
+    drop(store);
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect", "--key", "key1"])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].ends_with("live=false"));
+    assert!(lines[2].contains("key=\"key0\" "));
+    assert!(lines[2].ends_with("tombstone=true range_end=\"key2\" live=false"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server --config` should read settings from a TOML or JSON file, and
+// flags should override them.
+#[test]
+fn server_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "addr = \"127.0.0.1:4026\"\n\
+        data-dir = \"data\"\n\
+        pool = \"shared\"\n\
+        threads = 2\n\
+        log-level = \"warn\"\n\
+        compaction-threshold = 4096\n\
+        sync-mode = \"always\"\n\
+        codec = \"lz4\"\n\
+        compress-threshold = 16\n\
+        index-mode = \"disk\"\n\
+        max-file-size = 65536\n\
+        cache-size = 65536\n",
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml", "--addr", "127.0.0.1:4027"])
+        .args(["--pool", "rayon"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    // an idle connection should not keep others waiting
+    let idle = TcpStream::connect("127.0.0.1:4027").unwrap();
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key", "value", "--addr", "127.0.0.1:4027"])
+        .timeout(Duration::from_secs(5))
+        .assert()
+        .success();
+    drop(idle);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key", "--addr", "127.0.0.1:4027"])
+        .assert()
+        .success()
+        .stdout("value\n");
+    let long = "x".repeat(100);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "long", &long, "--addr", "127.0.0.1:4027"])
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+    assert!(temp_dir.path().join("data").join("identity").exists());
+    // compressed with LZ4
+    let data = fs::read(temp_dir.path().join("data").join("data-1")).unwrap();
+    assert!(!data.windows(long.len()).any(|w| w == long.as_bytes()));
+    assert!(!temp_dir.path().join(".kv_data").exists());
+
+    fs::write(temp_dir.path().join("kvs.json"), r#"{"log-level":"loud"}"#)
+        .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("invalid log level loud"));
+    fs::write(temp_dir.path().join("bad.toml"), "thread = 2\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "bad.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `thread`"));
+    fs::write(temp_dir.path().join("zstd.toml"), "codec = \"zstd\"\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "zstd.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("nonexistent codec zstd"));
+    fs::write(temp_dir.path().join("zero.toml"), "threads = 0\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "zero.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("at least 1 thread"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("--threads"));
+}
@@ -1,6 +1,10 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    Codec, Damage, EncryptionKey, Error, IndexMode, KvStore, KvStoreOptions,
+    KvsEngine, Result, SledStore, SyncMode,
+};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -223,3 +227,943 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Dump from one engine and load into another, the output should be ordered
+// by keyspace and key and skip removed keys.
+#[test]
+fn dump_and_load() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in (0..100).rev() {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    store.remove("key050".to_owned())?;
+    store
+        .keyspace("users")?
+        .set("a".to_owned(), "b".to_owned())?;
+
+    let mut output = Vec::new();
+    assert_eq!(kvs::dump(&store, &mut output)?, 100);
+    let lines: Vec<&str> = std::str::from_utf8(&output)
+        .expect("dump should be valid UTF-8")
+        .lines()
+        .collect();
+    assert_eq!(lines.len(), 100);
+    assert_eq!(lines[0], r#"{"key":"key000","value":"value0"}"#);
+    assert_eq!(lines[99], r#"{"keyspace":"users","key":"a","value":"b"}"#);
+    assert!(lines.windows(2).all(|w| w[0] < w[1]));
+
+    let sled = SledStore::open(temp_dir.path().join("sled"))?;
+    assert_eq!(kvs::load(&sled, output.as_slice())?, 100);
+    assert_eq!(sled.get("key050".to_owned())?, None);
+    assert_eq!(sled.get("a".to_owned())?, None);
+    let users = sled.keyspace("users")?;
+    assert_eq!(users.get("a".to_owned())?, Some("b".to_owned()));
+    for i in (0..100).filter(|i| *i != 50) {
+        assert_eq!(
+            sled.get(format!("key{:03}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    let mut reloaded = Vec::new();
+    kvs::dump(&sled, &mut reloaded)?;
+    assert_eq!(output, reloaded);
+
+    Ok(())
+}
+
+// Migrate data back and forth, the directory should be swapped with the
+// previous one kept as backup, and data of `kvs` should stay encrypted.
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data");
+    let options =
+        KvStoreOptions::new().encryption_key(EncryptionKey::new([1; 32]));
+    let store = KvStore::open_with(&path, options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store
+        .keyspace("users")?
+        .set("a".to_owned(), "b".to_owned())?;
+    drop(store);
+
+    assert!(kvs::migrate(&path, "sled", "kvs", &options).is_err());
+    let without_key = KvStoreOptions::new();
+    assert!(kvs::migrate(&path, "kvs", "sled", &without_key).is_err());
+    assert!(!temp_dir.path().join("data.sled").exists());
+    assert_eq!(kvs::migrate(&path, "kvs", "sled", &options)?, 100);
+    assert!(temp_dir.path().join("data.kvs.bak").is_dir());
+    assert_eq!(std::fs::read_to_string(path.join("identity"))?, "sled");
+    assert!(kvs::migrate(&path, "kvs", "sled", &options).is_err());
+
+    let store = SledStore::open(&path)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let users = store.keyspace("users")?;
+    assert_eq!(users.get("a".to_owned())?, Some("b".to_owned()));
+    drop((store, users));
+
+    assert_eq!(kvs::migrate(&path, "sled", "kvs", &options)?, 100);
+    assert!(temp_dir.path().join("data.sled.bak").is_dir());
+    // backups of previous migrations are never overwritten
+    assert!(kvs::migrate(&path, "kvs", "sled", &options).is_err());
+    let data = std::fs::read(path.join("data-1"))?;
+    assert!(!data.windows(5).any(|w| w == b"value"));
+    assert!(KvStore::open(&path).is_err());
+    let store = KvStore::open_with(&path, options)?;
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    let users = store.keyspace("users")?;
+    assert_eq!(users.get("a".to_owned())?, Some("b".to_owned()));
+
+    Ok(())
+}
+
+// Damage data files, then verify and repair them.
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:02}", i), format!("value{:02}", i))?;
+    }
+    store.set("key00".to_owned(), "overwritten".to_owned())?;
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?;
+    assert!(report.is_healthy());
+    assert_eq!(report.entries, 101);
+    assert!(report.garbage_bytes > 0);
+
+    // flip a byte in the middle and cut the tail of the data file
+    let path = temp_dir.path().join("data-1");
+    let mut data = std::fs::read(&path)?;
+    let offset = data
+        .windows(7)
+        .position(|w| w == b"value50")
+        .expect("value should be stored in plaintext");
+    data[offset] ^= 0xff;
+    data.truncate(data.len() - 3);
+    std::fs::write(&path, data)?;
+
+    let report = KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?;
+    assert!(!report.is_healthy());
+    assert_eq!(report.damages.len(), 2);
+    assert!(matches!(
+        report.damages[0],
+        Damage::Corrupt { file_id: 1, .. }
+    ));
+    assert!(matches!(
+        report.damages[1],
+        Damage::Truncated { file_id: 1, .. }
+    ));
+    assert_eq!(report.entries, 99);
+    assert_eq!(report.unreachable, 49);
+
+    // entries after the damaged one would be lost on open
+    for mode in [IndexMode::Memory, IndexMode::Disk] {
+        let options = KvStoreOptions::new().index_mode(mode);
+        match KvStore::open_with(temp_dir.path(), options) {
+            Err(Error::Corrupted(e)) => assert!(e.contains("kvs repair")),
+            _ => panic!("damaged data file is opened"),
+        }
+    }
+
+    let report = KvStore::repair(temp_dir.path(), &KvStoreOptions::new())?;
+    assert_eq!(report.quarantined, vec![1]);
+    assert!(temp_dir.path().join("quarantine").join("data-1").is_file());
+    assert!(
+        KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?.is_healthy()
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key00".to_owned())?, Some("value00".to_owned()));
+    assert_eq!(store.get("key50".to_owned())?, None);
+    assert_eq!(store.get("key51".to_owned())?, Some("value51".to_owned()));
+    assert_eq!(store.get("key98".to_owned())?, Some("value98".to_owned()));
+
+    Ok(())
+}
+
+// Every version of a key should be visited, and only the latest is live.
+#[test]
+fn inspect_entries() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value11".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let mut entries = Vec::new();
+    let damages =
+        KvStore::inspect(temp_dir.path(), &KvStoreOptions::new(), |e| {
+            entries.push(e)
+        })?;
+    assert!(damages.is_empty());
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|e| {
+            (
+                e.file_id,
+                e.seq,
+                e.key.as_str(),
+                e.value_len,
+                e.tombstone,
+                e.live,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (1, 1, "key1", 6, false, false),
+            (1, 2, "key2", 6, false, false),
+            (2, 3, "key1", 7, false, true),
+            (2, 4, "key2", 0, true, false),
+        ]
+    );
+    assert!(entries.windows(2).all(|w| w[0].file_id < w[1].file_id
+        || w[0].offset + w[0].size == w[1].offset));
+
+    Ok(())
+}
+
+// Compressed values should be readable with any codec, and compaction should
+// recompress them with the current one.
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|res| res.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+    let value =
+        |i| format!(r#"{{"id":{},"tags":"{}"}}"#, i, "kvs,".repeat(256));
+
+    let options = KvStoreOptions::new()
+        .codec(Codec::Lz4)
+        .compress_threshold(64);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), value(i))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    let compressed_size = dir_size();
+    assert!(compressed_size < 100 * value(0).len() as u64 / 4);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    // values are written uncompressed from now on
+    store.compact()?;
+    assert!(dir_size() > 100 * value(0).len() as u64);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+
+    Ok(())
+}
+
+// Encrypted data should only be readable with its key, and compaction should
+// rotate old keys.
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let old_key = EncryptionKey::new([1; 32]);
+    let new_key = EncryptionKey::from_hex(&"2".repeat(64))?;
+    assert_ne!(old_key.id(), new_key.id());
+
+    let options = KvStoreOptions::new().encryption_key(old_key.clone());
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("secret{}", i))?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    drop(store);
+
+    let data = std::fs::read(temp_dir.path().join("data-1"))?;
+    assert!(!data.windows(6).any(|w| w == b"secret"));
+    assert!(!data.windows(4).any(|w| w == b"key1"));
+
+    // without the key, or with a wrong one
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(err.to_string().contains("encrypted with key"));
+    let options = KvStoreOptions::new().encryption_key(new_key.clone());
+    assert!(KvStore::open_with(temp_dir.path(), options.clone()).is_err());
+
+    // rotate to the new key
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        options.clone().decryption_key(old_key),
+    )?;
+    store.set("key100".to_owned(), "secret100".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..=100 {
+        assert_eq!(
+            store.get(format!("key{}", i))?,
+            Some(format!("secret{}", i))
+        );
+    }
+    drop(store);
+    assert!(KvStore::verify(temp_dir.path(), &options)?.is_healthy());
+
+    Ok(())
+}
+
+// A store with an on-disk index should behave like an in-memory one across
+// sealed files, reopening and compaction.
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .index_mode(IndexMode::Disk)
+        .max_file_size(4096);
+    let expected = |i: usize| match i {
+        i if i % 5 == 0 => None,
+        i if i % 3 == 0 => Some(format!("new{}", i)),
+        i => Some(format!("value{}", i)),
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..1000 {
+            assert_eq!(store.get(format!("key{:04}", i))?, expected(i));
+        }
+        let pairs = store
+            .scan_prefix("key00".to_owned())?
+            .collect::<Result<Vec<_>>>()?;
+        let keys: Vec<_> =
+            (0..100).filter(|&i| expected(i).is_some()).collect();
+        assert_eq!(pairs.len(), keys.len());
+        for ((key, value), i) in pairs.into_iter().zip(keys) {
+            assert_eq!(key, format!("key{:04}", i));
+            assert_eq!(Some(value), expected(i));
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    for i in (0..1000).filter(|i| i % 3 == 0) {
+        store.set(format!("key{:04}", i), format!("new{}", i))?;
+    }
+    for i in (0..1000).step_by(5) {
+        store.remove(format!("key{:04}", i))?;
+    }
+    assert!(temp_dir.path().join("index-1").exists());
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    store.set("key0000".to_owned(), "again".to_owned())?;
+    drop(store);
+
+    assert!(KvStore::verify(temp_dir.path(), &options)?.is_healthy());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0000".to_owned())?, Some("again".to_owned()));
+    store.remove("key0000".to_owned())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Cached values should be shared by clones, bounded in size and never
+// outlive writes or compaction.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cache_size(4096);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(KvStore::open(temp_dir.path())?.cache_stats(), None);
+
+    store.set("key".to_owned(), "value1".to_owned())?;
+    let clone = store.clone();
+    assert_eq!(store.get("key".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(clone.get("key".to_owned())?, Some("value1".to_owned()));
+    let stats = store.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+    clone.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value2".to_owned()));
+    store.remove("key".to_owned())?;
+    assert_eq!(clone.get("key".to_owned())?, None);
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), "x".repeat(100))?;
+        store.get(format!("key{}", i))?;
+    }
+    let stats = store.cache_stats().unwrap();
+    assert!(stats.bytes <= 4096);
+    assert!(stats.entries > 0 && stats.entries < 100);
+
+    store.compact()?;
+    assert_eq!(store.cache_stats().unwrap().entries, 0);
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("x".repeat(100)));
+    }
+
+    Ok(())
+}
+
+// Watches should receive changes of keys under their prefixes in order.
+#[test]
+fn watch_prefix() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        let mut watch = store.watch("app/".to_owned())?;
+        store.set("app/a".to_owned(), "1".to_owned())?;
+        store.set("other".to_owned(), "2".to_owned())?;
+        store.set("app/b".to_owned(), "3".to_owned())?;
+        store.remove("app/a".to_owned())?;
+
+        let events = (0..3)
+            .map(|_| watch.next().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        let changes: Vec<_> = events
+            .iter()
+            .map(|e| (e.key.as_str(), e.value.as_deref()))
+            .collect();
+        assert_eq!(
+            changes,
+            [("app/a", Some("1")), ("app/b", Some("3")), ("app/a", None)]
+        );
+        assert!(events.windows(2).all(|w| w[0].seq < w[1].seq));
+        assert!(watch.next_timeout(Duration::from_millis(10)).is_none());
+        assert!(!watch.is_ended());
+
+        // a dropped watch is forgotten by writers
+        drop(watch);
+        store.set("app/c".to_owned(), "4".to_owned())?;
+        Ok(())
+    }
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(SledStore::open(temp_dir.path())?)
+}
+
+// A watch falling too far behind should get the changes it kept, then end,
+// without holding back writers.
+#[test]
+fn watch_lagging() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut watch = store.watch(String::new())?;
+    for i in 0..2000 {
+        store.set(format!("key{i}"), i.to_string())?;
+    }
+    let events = watch.by_ref().collect::<Result<Vec<_>>>()?;
+    assert_eq!(events.len(), 1024);
+    assert_eq!(events[1023].key, "key1023");
+    assert!(watch.is_ended());
+    Ok(())
+}
+
+// Keys in a keyspace should be isolated from the default one and other
+// keyspaces, also after reopening.
+#[test]
+fn keyspaces() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        let users = store.keyspace("users")?;
+        let orders = store.keyspace("orders")?;
+        store.set("a".to_owned(), "default".to_owned())?;
+        users.set("a".to_owned(), "user".to_owned())?;
+        users.set("b".to_owned(), "user".to_owned())?;
+        orders.set("a".to_owned(), "order".to_owned())?;
+        orders.remove("a".to_owned())?;
+
+        assert_eq!(store.get("a".to_owned())?, Some("default".to_owned()));
+        assert_eq!(users.get("a".to_owned())?, Some("user".to_owned()));
+        assert_eq!(orders.get("a".to_owned())?, None);
+        assert!(orders.remove("b".to_owned()).is_err());
+        let keys = users
+            .scan(..)?
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, ["a", "b"]);
+        let keys = store
+            .scan(..)?
+            .map(|kv| kv.map(|(k, _)| k))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, ["a"]);
+        assert!(store.keyspace("").is_err());
+        assert!(users.keyspaces()?.contains(&"users".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    // keyspaces without keys are gone
+    assert_eq!(store.keyspaces()?, ["users"]);
+    let users = store.keyspace("users")?;
+    assert_eq!(users.get("b".to_owned())?, Some("user".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    drop((store, users));
+
+    let mut keyspaces = Vec::new();
+    KvStore::inspect(temp_dir.path(), &KvStoreOptions::new(), |e| {
+        keyspaces.push((e.keyspace, e.key))
+    })?;
+    assert_eq!(keyspaces[0], (None, "a".to_owned()));
+    assert_eq!(keyspaces[1], (Some("users".to_owned()), "a".to_owned()));
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(SledStore::open(temp_dir.path())?)
+}
+
+// Concurrent increments should not lose updates, and a failed merge should
+// keep the old value.
+#[test]
+fn increment_and_merge() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        store.increment("counter".to_owned(), 2).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(store.get("counter".to_owned())?, Some("1600".to_owned()));
+        assert_eq!(store.increment("counter".to_owned(), -1600)?, 0);
+
+        store.set("name".to_owned(), "kvs".to_owned())?;
+        assert!(store.increment("name".to_owned(), 1).is_err());
+        assert_eq!(store.get("name".to_owned())?, Some("kvs".to_owned()));
+
+        let append = |old: Option<&str>| Ok(Some(format!("{}!", old.unwrap())));
+        assert_eq!(
+            store.merge("name".to_owned(), append)?,
+            Some("kvs!".to_owned())
+        );
+        assert_eq!(store.merge("name".to_owned(), |_| Ok(None))?, None);
+        assert_eq!(store.get("name".to_owned())?, None);
+        Ok(())
+    }
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(SledStore::open(temp_dir.path())?)
+}
+
+// Concurrent transactions moving amounts between keys should keep their sum,
+// and a failed transaction should write nothing.
+#[test]
+fn transactions() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        store.set("a".to_owned(), "100".to_owned())?;
+        store.set("b".to_owned(), "100".to_owned())?;
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    let (from, to) =
+                        if i % 2 == 0 { ("a", "b") } else { ("b", "a") };
+                    for _ in 0..50 {
+                        store
+                            .transaction(|tx| {
+                                let x: i64 = tx
+                                    .get(from.to_owned())?
+                                    .unwrap()
+                                    .parse()?;
+                                let y: i64 =
+                                    tx.get(to.to_owned())?.unwrap().parse()?;
+                                tx.set(from.to_owned(), (x - 1).to_string())?;
+                                tx.set(to.to_owned(), (y + 1).to_string())
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        let sum = store
+            .scan(..)?
+            .map(|kv| Ok(kv?.1.parse::<i64>()?))
+            .sum::<Result<i64>>()?;
+        assert_eq!(sum, 200);
+
+        let result = store.transaction(|tx| {
+            tx.set("c".to_owned(), "1".to_owned())?;
+            assert_eq!(tx.get("c".to_owned())?, Some("1".to_owned()));
+            tx.remove("a".to_owned())?;
+            assert_eq!(tx.get("a".to_owned())?, None);
+            tx.remove("d".to_owned())
+        });
+        assert!(matches!(result, Err(Error::NonexistentKey)));
+        assert_eq!(store.get("c".to_owned())?, None);
+        assert!(store.get("a".to_owned())?.is_some());
+        Ok(())
+    }
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(SledStore::open(temp_dir.path())?)
+}
+
+// A transaction cut before its last entry should be skipped as a whole on
+// open, and dropped by repair.
+#[test]
+fn unfinished_transaction() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "0".to_owned())?;
+    store.set("b".to_owned(), "0".to_owned())?;
+    store.transaction(|tx| {
+        tx.set("a".to_owned(), "1".to_owned())?;
+        tx.set("b".to_owned(), "1".to_owned())
+    })?;
+    drop(store);
+
+    let mut entries = Vec::new();
+    KvStore::inspect(temp_dir.path(), &KvStoreOptions::new(), |e| {
+        entries.push((e.file_id, e.offset, e.size))
+    })?;
+    let (file_id, first, _) = entries[2];
+    let (_, last, size) = entries[3];
+    let path = temp_dir.path().join(format!("data-{file_id}"));
+    let file = std::fs::OpenOptions::new().write(true).open(&path)?;
+    file.set_len(last + size - 1)?;
+    drop(file);
+
+    for mode in [IndexMode::Memory, IndexMode::Disk] {
+        let options = KvStoreOptions::new().index_mode(mode);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("a".to_owned())?, Some("0".to_owned()));
+        assert_eq!(store.get("b".to_owned())?, Some("0".to_owned()));
+    }
+
+    let report = KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?;
+    assert_eq!(
+        report.damages[0],
+        Damage::Unfinished {
+            file_id,
+            offset: first,
+            len: last - first,
+        }
+    );
+    KvStore::repair(temp_dir.path(), &KvStoreOptions::new())?;
+    assert!(
+        KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?.is_healthy()
+    );
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("a".to_owned())?, Some("0".to_owned()));
+
+    Ok(())
+}
+
+// Data files of the version without file headers should be refused on open,
+// and readable after `upgrade`, which removes keys with empty values.
+#[test]
+fn upgrade_legacy_files() -> Result<()> {
+    fn legacy(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (key, value) in entries {
+            buf.extend(1_700_000_000i64.to_ne_bytes());
+            buf.extend(key.len().to_ne_bytes());
+            buf.extend(value.len().to_ne_bytes());
+            buf.extend(key.as_bytes());
+            buf.extend(value.as_bytes());
+        }
+        buf
+    }
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path();
+    std::fs::write(
+        dir.join("data-1"),
+        legacy(&[("a", "1"), ("b", "2"), ("c", "3")]),
+    )?;
+    let mut data = legacy(&[("b", ""), ("a", "4")]);
+    // torn tail, which the previous version skipped
+    data.extend(&legacy(&[("d", "5")])[..10]);
+    std::fs::write(dir.join("data-2"), data)?;
+
+    let err = KvStore::open(dir).err().expect("legacy files are opened");
+    assert!(err.to_string().contains("kvs upgrade"));
+    assert!(KvStore::verify(dir, &KvStoreOptions::new()).is_err());
+
+    assert_eq!(KvStore::upgrade(dir, &KvStoreOptions::new())?, 2);
+    assert_eq!(KvStore::upgrade(dir, &KvStoreOptions::new())?, 0);
+    assert!(KvStore::verify(dir, &KvStoreOptions::new())?.is_healthy());
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("a".to_owned())?, Some("4".to_owned()));
+    assert_eq!(store.get("b".to_owned())?, None);
+    assert_eq!(store.get("c".to_owned())?, Some("3".to_owned()));
+    assert_eq!(store.get("d".to_owned())?, None);
+    store.set("b".to_owned(), "6".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(dir)?;
+    assert_eq!(store.get("b".to_owned())?, Some("6".to_owned()));
+
+    Ok(())
+}
+
+// Compacting with an on-disk index, which orders entries by key, should keep
+// an entry of a transaction that ends up last in the new data file.
+#[test]
+fn compact_transaction_entry() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_mode(IndexMode::Disk);
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("a".to_owned(), "0".to_owned())?;
+    store.transaction(|tx| {
+        tx.set("m".to_owned(), "1".to_owned())?;
+        tx.set("z".to_owned(), "1".to_owned())
+    })?;
+    store.remove("z".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    assert!(
+        KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?.is_healthy()
+    );
+    for path in WalkDir::new(temp_dir.path()).min_depth(1) {
+        let path = path.expect("unable to walk the directory").into_path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with("index-") {
+            std::fs::remove_file(path)?;
+        }
+    }
+    for options in [options, KvStoreOptions::new()] {
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("a".to_owned())?, Some("0".to_owned()));
+        assert_eq!(store.get("m".to_owned())?, Some("1".to_owned()));
+        assert_eq!(store.get("z".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// Removing a range should leave keys outside it and other keyspaces, also
+// after reopening with either index mode.
+#[test]
+fn remove_range_and_clear() -> Result<()> {
+    fn keys(store: &impl KvsEngine) -> Result<Vec<String>> {
+        store.scan(..)?.map(|kv| kv.map(|(k, _)| k)).collect()
+    }
+
+    fn check(store: impl KvsEngine) -> Result<()> {
+        for key in ["a", "t1/x", "t1/y", "t1\u{10ffff}", "t2/x", "z"] {
+            store.set(key.to_owned(), "value".to_owned())?;
+        }
+        let other = store.keyspace("other")?;
+        other.set("t1/x".to_owned(), "value".to_owned())?;
+
+        store.remove_prefix("t1/".to_owned())?;
+        assert_eq!(keys(&store)?, ["a", "t1\u{10ffff}", "t2/x", "z"]);
+        store.remove_range("b".to_owned()..="t2/x".to_owned())?;
+        assert_eq!(keys(&store)?, ["a", "z"]);
+        store.remove_range("z".to_owned().."a".to_owned())?;
+        assert_eq!(keys(&store)?, ["a", "z"]);
+        assert_eq!(keys(&other)?, ["t1/x"]);
+
+        other.clear()?;
+        assert!(keys(&other)?.is_empty());
+        assert_eq!(keys(&store)?, ["a", "z"]);
+        Ok(())
+    }
+
+    for mode in [IndexMode::Memory, IndexMode::Disk] {
+        let temp_dir = TempDir::new()
+            .expect("unable to create temporary working directory");
+        let options = KvStoreOptions::new().index_mode(mode);
+        check(KvStore::open_with(temp_dir.path(), options.clone())?)?;
+
+        let mut ranges = 0;
+        KvStore::inspect(temp_dir.path(), &options, |e| {
+            ranges += e.range_end.is_some() as usize;
+        })?;
+        // `clear` compacts them away
+        assert_eq!(ranges, 0);
+
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("t3/x".to_owned(), "value".to_owned())?;
+        // removed keys are in a previous data file
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.remove_prefix("t3/".to_owned())?;
+        assert_eq!(keys(&store)?, ["a", "z"]);
+        drop(store);
+        let mut ranges = 0;
+        KvStore::inspect(temp_dir.path(), &options, |e| {
+            ranges += e.range_end.is_some() as usize;
+        })?;
+        assert_eq!(ranges, 1);
+        for mode in [IndexMode::Memory, IndexMode::Disk] {
+            let options = options.clone().index_mode(mode);
+            let store = KvStore::open_with(temp_dir.path(), options)?;
+            assert_eq!(keys(&store)?, ["a", "z"]);
+            assert!(KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?
+                .is_healthy());
+        }
+    }
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(SledStore::open(temp_dir.path())?)
+}
+
+// Values of many keys should come back in the order of the keys, whether
+// they are cached or not.
+#[test]
+fn get_many_and_set_many() -> Result<()> {
+    fn check(store: impl KvsEngine) -> Result<()> {
+        let pairs: Vec<_> = (0..100)
+            .map(|i| (format!("key{i}"), format!("value{i}")))
+            .collect();
+        store.set_many(pairs)?;
+        store.set("key7".to_owned(), "changed".to_owned())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+        let keys = ["key9", "key3", "missing", "key7", "key99", "key3"];
+        let values = store.get_many(keys.map(str::to_owned).to_vec())?;
+        assert_eq!(
+            values,
+            [
+                Some("value9".to_owned()),
+                Some("value3".to_owned()),
+                None,
+                Some("changed".to_owned()),
+                Some("value99".to_owned()),
+                Some("value3".to_owned()),
+            ]
+        );
+        Ok(())
+    }
+
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cache_size(1 << 10);
+    check(KvStore::open_with(temp_dir.path(), options)?)?;
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    check(SledStore::open(temp_dir.path())?)
+}
+
+// Stale data should be compacted once it passes the configured threshold,
+// with every write synced.
+#[test]
+fn compaction_threshold_and_sync() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|res| res.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .sync_mode(SyncMode::Always);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..1000 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    // 1000 entries would take about 40 KiB without compaction
+    assert!(dir_size() < 8192);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value999".to_owned()));
+
+    Ok(())
+}
+
+// A data file with a damaged header should be reported and quarantined.
+#[test]
+fn verify_damaged_header() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    std::fs::write(temp_dir.path().join("data-5"), [0xff; 64])?;
+
+    let report = KvStore::verify(temp_dir.path(), &KvStoreOptions::new())?;
+    assert!(matches!(
+        report.damages[..],
+        [Damage::Corrupt {
+            file_id: 5,
+            offset: 0,
+            len: 64
+        }]
+    ));
+
+    let report = KvStore::repair(temp_dir.path(), &KvStoreOptions::new())?;
+    assert_eq!(report.quarantined, vec![5]);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should refuse a data file whose id does not fit into the index
+#[test]
+fn file_id_out_of_range() -> Result<()> {
+    let temp_dir =
+        TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let id_list: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().contains("data-"))
+        .collect();
+    std::fs::copy(&id_list[0], temp_dir.path().join("data-4294967296"))?;
+
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    Ok(())
+}